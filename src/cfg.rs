@@ -1,5 +1,10 @@
 use irc;
 use slack_client;
+use outbox;
+use journal;
+use direct;
+use loops;
+use filters;
 
 use std::collections::HashMap;
 
@@ -10,16 +15,26 @@ pub struct Cfg {
     #[serde(rename="slack")]
     pub slack_cfg: slack_client::SlackCfg,
     pub channels: HashMap<String, String>,
+    pub outbox: Option<outbox::OutboxCfg>,
+    pub journal: Option<journal::JournalCfg>,
+    pub direct_messages: Option<direct::DirectCfg>,
+    pub topics: Option<irc::TopicCfg>,
+    pub presence: Option<irc::PresenceCfg>,
+    pub loops: Option<loops::LoopCfg>,
+    pub filters: Option<Vec<filters::RuleCfg>>,
 }
 
 impl Cfg {
     pub fn get_cfg(self) -> (irc::IrcCfg, slack_client::SlackCfg) {
-        let Cfg { mut irc_cfg, mut slack_cfg, channels } = self;
+        let Cfg { mut irc_cfg, mut slack_cfg, channels, outbox, topics, presence, .. } = self;
         let slack_chans = channels.iter()
             .map(|(irc, slack)| (slack.to_string(), irc.to_string()))
             .collect();
         slack_cfg.channels = slack_chans;
         irc_cfg.channels = channels;
+        irc_cfg.outbox = outbox.unwrap_or_default();
+        irc_cfg.topics = topics;
+        irc_cfg.presence = presence;
         (irc_cfg, slack_cfg)
     }
 }
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const HELP: &'static str =
+    "commands: !names, !whois <nick>, !topic, !status, !filter <rule> <text>, !help";
+
+// slack users count as active for this long after their last message
+const ACTIVE_WINDOW: u64 = 3600;
+
+/// A request to the relay, sent as a message starting with `!`. Commands are
+/// answered to the side they came from and never relayed.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Names,
+    Whois(String),
+    Topic,
+    Status,
+    // a filter rule and a sample message to try it on
+    Filter(String, String),
+    // a command missing its arguments, answered with how to use it
+    Usage(&'static str),
+    Help,
+}
+
+impl Command {
+    pub fn parse(text: &str) -> Option<Command> {
+        let mut words = text.trim().split_whitespace();
+        let cmd = match words.next() {
+            Some(w) if w.starts_with('!') => w[1..].to_lowercase(),
+            _ => return None,
+        };
+        match cmd.as_str() {
+            "names" => Some(Command::Names),
+            "whois" => Some(match words.next() {
+                Some(n) => Command::Whois(n.to_owned()),
+                None => Command::Usage("usage: !whois <nick>"),
+            }),
+            "topic" => Some(Command::Topic),
+            "status" => Some(Command::Status),
+            "filter" => {
+                let mut args = text.trim().splitn(3, char::is_whitespace).skip(1);
+                Some(match (args.next(), args.next()) {
+                    (Some(rule), Some(sample)) if !sample.trim().is_empty() => {
+                        Command::Filter(rule.to_owned(), sample.trim().to_owned())
+                    }
+                    _ => Command::Usage("usage: !filter <rule> <text>"),
+                })
+            }
+            "help" => Some(Command::Help),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Roster {
+    // last message time of slack users by IRC channel
+    active: HashMap<String, HashMap<String, Instant>>,
+    // slack channel topics by IRC channel
+    topics: HashMap<String, String>,
+    connected: bool,
+}
+
+/// What the relay knows about slack, for answering commands from IRC.
+#[derive(Clone, Default)]
+pub struct SlackRoster(Arc<Mutex<Roster>>);
+
+impl SlackRoster {
+    pub fn spoke(&self, chan: &str, nick: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .active
+            .entry(chan.to_owned())
+            .or_insert_with(HashMap::new)
+            .insert(nick.to_owned(), Instant::now());
+    }
+
+    /// Slack users that recently spoke in the channel mapped to an IRC channel.
+    pub fn active(&self, chan: &str) -> Vec<String> {
+        let window = Duration::from_secs(ACTIVE_WINDOW);
+        let roster = self.0.lock().unwrap();
+        let mut nicks: Vec<String> = roster
+            .active
+            .get(chan)
+            .iter()
+            .flat_map(|users| users.iter())
+            .filter(|&(_, at)| at.elapsed() < window)
+            .map(|(nick, _)| nick.clone())
+            .collect();
+        nicks.sort();
+        nicks
+    }
+
+    /// Where and how long ago a slack user last spoke.
+    pub fn last_seen(&self, nick: &str) -> Option<(String, Duration)> {
+        let roster = self.0.lock().unwrap();
+        roster
+            .active
+            .iter()
+            .filter_map(|(chan, users)| users.get(nick).map(|at| (chan.clone(), at.elapsed())))
+            .min_by_key(|&(_, ago)| ago)
+    }
+
+    pub fn set_topic(&self, chan: &str, topic: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .topics
+            .insert(chan.to_owned(), topic.to_owned());
+    }
+
+    pub fn topic(&self, chan: &str) -> Option<String> {
+        self.0.lock().unwrap().topics.get(chan).cloned()
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.0.lock().unwrap().connected = connected;
+    }
+
+    pub fn connected(&self) -> bool {
+        self.0.lock().unwrap().connected
+    }
+}
+
+/// e.g. `5m` or `2h`
+pub fn format_ago(ago: Duration) -> String {
+    let secs = ago.as_secs();
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s => format!("{}h", s / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse(" !NAMES "), Some(Command::Names));
+        assert_eq!(Command::parse("!whois bob"), Some(Command::Whois("bob".to_owned())));
+        assert_eq!(
+            Command::parse("!filter secrets my password is hunter2"),
+            Some(Command::Filter("secrets".to_owned(), "my password is hunter2".to_owned()))
+        );
+        assert_eq!(Command::parse("names"), None);
+        assert_eq!(Command::parse("!unknown"), None);
+    }
+
+    #[test]
+    fn answers_incomplete_commands_with_usage() {
+        assert_eq!(Command::parse("!whois"), Some(Command::Usage("usage: !whois <nick>")));
+        let usage = Some(Command::Usage("usage: !filter <rule> <text>"));
+        assert_eq!(Command::parse("!filter"), usage);
+        assert_eq!(Command::parse("!filter secrets  "), usage);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use message::PrivMsg;
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DirectCfg {
+    /// Slack users that can be messaged privately from IRC and vice versa.
+    slack_users: Vec<String>,
+}
+
+struct Conversation {
+    irc_nick: String,
+    // ordinal of the latest message, the highest being the most recent
+    last: u64,
+}
+
+#[derive(Default)]
+struct Pairs {
+    // by lower case IRC nick and slack user
+    conversations: HashMap<(String, String), Conversation>,
+    count: u64,
+}
+
+impl Pairs {
+    // The other side of the conversation a user took part in most recently.
+    fn latest<F>(&self, with: F) -> Option<(&(String, String), &Conversation)>
+    where
+        F: Fn(&(String, String)) -> bool,
+    {
+        self.conversations
+            .iter()
+            .filter(|&(pair, _)| with(pair))
+            .max_by_key(|&(_, c)| c.last)
+    }
+}
+
+/// Private conversations between IRC and slack users, one per pair of them. A
+/// message that isn't addressed as `name: text` goes to whoever the sender
+/// talked to last.
+#[derive(Clone)]
+pub struct Conversations {
+    opted_in: Arc<HashSet<String>>,
+    pairs: Arc<Mutex<Pairs>>,
+}
+
+// Splits `name: text` into the name and the text.
+fn addressed(text: &str) -> Option<(&str, &str)> {
+    let idx = text.find(": ")?;
+    let name = &text[..idx];
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((name, text[idx + 2..].trim_left()))
+}
+
+impl Conversations {
+    pub fn new(cfg: DirectCfg) -> Conversations {
+        Conversations {
+            opted_in: Arc::new(cfg.slack_users.into_iter().collect()),
+            pairs: Arc::new(Mutex::new(Pairs::default())),
+        }
+    }
+
+    pub fn opted_in(&self, slack_user: &str) -> bool {
+        self.opted_in.contains(slack_user)
+    }
+
+    fn talked(&self, irc_nick: &str, slack_user: &str) {
+        let mut pairs = self.pairs.lock().unwrap();
+        pairs.count += 1;
+        let conversation = Conversation {
+            irc_nick: irc_nick.to_owned(),
+            last: pairs.count,
+        };
+        pairs
+            .conversations
+            .insert((irc_nick.to_lowercase(), slack_user.to_owned()), conversation);
+    }
+
+    fn partner_of_irc(&self, irc_nick: &str) -> Option<String> {
+        let irc_nick = irc_nick.to_lowercase();
+        let pairs = self.pairs.lock().unwrap();
+        let partner = pairs
+            .latest(|pair| pair.0 == irc_nick)
+            .map(|(pair, _)| pair.1.clone());
+        partner
+    }
+
+    fn partner_of_slack(&self, slack_user: &str) -> Option<String> {
+        let pairs = self.pairs.lock().unwrap();
+        let partner = pairs
+            .latest(|pair| pair.1 == slack_user)
+            .map(|(_, c)| c.irc_nick.clone());
+        partner
+    }
+
+    /// Works out which slack user a private message from IRC is for, or why it
+    /// can't be delivered.
+    pub fn from_irc(&self, mut msg: PrivMsg) -> Result<PrivMsg, String> {
+        let partner = self.partner_of_irc(&msg.nick);
+        let (to, text) = match (addressed(&msg.msg), partner) {
+            (Some((to, text)), _) if self.opted_in(to) => (to.to_owned(), text.to_owned()),
+            (Some((to, _)), None) => {
+                return Err(format!("{} doesn't take private messages", to));
+            }
+            (_, Some(partner)) => (partner, msg.msg.clone()),
+            (None, None) => {
+                return Err("address a slack user as `name: message`".to_owned());
+            }
+        };
+        self.talked(&msg.nick, &to);
+        msg.chan = to;
+        msg.msg = text;
+        Ok(msg)
+    }
+
+    /// Works out which IRC nick a private message from slack is for. Messages
+    /// from users that haven't opted in are dropped.
+    pub fn from_slack(&self, mut msg: PrivMsg) -> Option<PrivMsg> {
+        if !self.opted_in(&msg.nick) {
+            return None;
+        }
+        let (to, text) = match addressed(&msg.msg) {
+            Some((to, text)) => (to.to_owned(), text.to_owned()),
+            None => (self.partner_of_slack(&msg.nick)?, msg.msg.clone()),
+        };
+        self.talked(&to, &msg.nick);
+        msg.chan = to;
+        msg.msg = text;
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversations() -> Conversations {
+        Conversations::new(DirectCfg {
+            slack_users: vec!["alice".to_owned(), "carol".to_owned()],
+        })
+    }
+
+    fn msg(nick: &str, text: &str) -> PrivMsg {
+        PrivMsg {
+            nick: nick.to_owned(),
+            msg: text.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn addresses_opted_in_users() {
+        let convs = conversations();
+        let m = convs.from_irc(msg("Bob", "alice: hi there")).unwrap();
+        assert_eq!((m.chan.as_str(), m.msg.as_str()), ("alice", "hi there"));
+        assert!(convs.from_irc(msg("Eve", "dave: hi")).is_err());
+        assert!(convs.from_irc(msg("Eve", "hi")).is_err());
+        assert!(convs.from_slack(msg("dave", "Bob: hi")).is_none());
+    }
+
+    #[test]
+    fn conversations_are_kept_per_pair() {
+        let convs = conversations();
+        convs.from_irc(msg("Bob", "alice: hi")).unwrap();
+        convs.from_irc(msg("Dan", "alice: hello")).unwrap();
+        convs.from_irc(msg("Dan", "carol: hey")).unwrap();
+
+        // alice's last conversation was with Dan, Bob's is still with alice
+        let m = convs.from_slack(msg("alice", "yes?")).unwrap();
+        assert_eq!(m.chan, "Dan");
+        let m = convs.from_irc(msg("bob", "again")).unwrap();
+        assert_eq!(m.chan, "alice");
+        // which makes Bob alice's latest partner
+        let m = convs.from_slack(msg("alice", "what?")).unwrap();
+        assert_eq!(m.chan, "bob");
+        // while Dan's is still alice rather than carol
+        let m = convs.from_irc(msg("Dan", "still there?")).unwrap();
+        assert_eq!(m.chan, "alice");
+    }
+}
@@ -4,6 +4,7 @@ use slack;
 use slack_hook;
 use config;
 use irc;
+use hyper;
 
 
 error_chain!{
@@ -26,5 +27,6 @@ error_chain!{
         Slack(slack::Error);
         Io(::std::io::Error);
         CfgError(config::ConfigError);
+        Uri(hyper::error::UriError);
     }
 }
@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use regex::{self, Regex};
+
+use journal::Direction;
+
+const REDACTED: &'static str = "[redacted]";
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Drop,
+    Redact,
+    Replace,
+    Warn,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    ToIrc,
+    ToSlack,
+    Both,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RuleCfg {
+    name: String,
+    pattern: String,
+    action: Action,
+    /// What matches are replaced with, may refer to groups as `$1`.
+    replacement: Option<String>,
+    /// IRC channels the rule applies to, all of them if omitted. Direct
+    /// messages aren't in any channel, so every rule applies to them.
+    channels: Option<Vec<String>>,
+    /// Defaults to both.
+    direction: Option<Scope>,
+}
+
+struct Rule {
+    cfg: RuleCfg,
+    regex: Regex,
+}
+
+impl Rule {
+    fn applies(&self, dir: Direction, chan: Option<&str>) -> bool {
+        let dir_matches = match (self.cfg.direction.unwrap_or(Scope::Both), dir) {
+            (Scope::Both, _) | (Scope::ToIrc, Direction::ToIrc) => true,
+            (Scope::ToSlack, Direction::ToSlack) => true,
+            _ => false,
+        };
+        let chan_matches = match (self.cfg.channels.as_ref(), chan) {
+            (Some(chans), Some(chan)) => chans.iter().any(|c| c.eq_ignore_ascii_case(chan)),
+            _ => true,
+        };
+        dir_matches && chan_matches
+    }
+
+    fn rewrite(&self, text: &str) -> String {
+        let replacement = match self.cfg.action {
+            Action::Replace => self.cfg.replacement.as_ref().map(|r| r.as_str()).unwrap_or(""),
+            _ => REDACTED,
+        };
+        self.regex.replace_all(text, replacement).into_owned()
+    }
+}
+
+/// What becomes of a message.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// Relayed, possibly with parts of it redacted or replaced.
+    Relay(String),
+    Drop(String),
+    /// Not relayed, and the sender is told which rule stopped it.
+    Warn(String),
+}
+
+/// Rules for what may be relayed, checked in order on every message. Rules
+/// that drop a message stop the check.
+#[derive(Clone, Default)]
+pub struct Filters(Arc<Vec<Rule>>);
+
+impl Filters {
+    pub fn new(rules: Vec<RuleCfg>) -> Result<Filters, regex::Error> {
+        let rules = rules
+            .into_iter()
+            .map(|cfg| {
+                Ok(Rule {
+                    regex: Regex::new(&cfg.pattern)?,
+                    cfg: cfg,
+                })
+            })
+            .collect::<Result<Vec<_>, regex::Error>>()?;
+        Ok(Filters(Arc::new(rules)))
+    }
+
+    pub fn apply(&self, dir: Direction, chan: &str, text: &str) -> Verdict {
+        self.check(dir, Some(chan), text)
+    }
+
+    /// Like apply(), for a direct message, which isn't in any channel.
+    pub fn apply_direct(&self, dir: Direction, text: &str) -> Verdict {
+        self.check(dir, None, text)
+    }
+
+    fn check(&self, dir: Direction, chan: Option<&str>, text: &str) -> Verdict {
+        let mut text = text.to_owned();
+        for rule in self.0.iter().filter(|r| r.applies(dir, chan)) {
+            if !rule.regex.is_match(&text) {
+                continue;
+            }
+            let to = chan.unwrap_or("a direct message");
+            debug!("filter {} matched a message to {}", rule.cfg.name, to);
+            match rule.cfg.action {
+                Action::Drop => return Verdict::Drop(rule.cfg.name.clone()),
+                Action::Warn => return Verdict::Warn(rule.cfg.name.clone()),
+                Action::Redact | Action::Replace => text = rule.rewrite(&text),
+            }
+        }
+        Verdict::Relay(text)
+    }
+
+    /// Describes what a rule would do to a sample message, wherever it applies.
+    pub fn test(&self, name: &str, sample: &str) -> String {
+        let rule = match self.0.iter().find(|r| r.cfg.name == name) {
+            Some(r) => r,
+            None => return format!("there's no filter called {}", name),
+        };
+        if !rule.regex.is_match(sample) {
+            return format!("{} doesn't match", name);
+        }
+        match rule.cfg.action {
+            Action::Drop => format!("{} would drop it", name),
+            Action::Warn => format!("{} would stop it and warn the sender", name),
+            Action::Redact | Action::Replace => {
+                format!("{} would relay it as: {}", name, rule.rewrite(sample))
+            }
+        }
+    }
+}
+
+/// What the sender of a message stopped by a rule is told.
+pub fn warning(rule: &str) -> String {
+    format!("your message wasn't relayed because it matches the {} filter", rule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, pattern: &str, action: Action) -> RuleCfg {
+        RuleCfg {
+            name: name.to_owned(),
+            pattern: pattern.to_owned(),
+            action: action,
+            replacement: None,
+            channels: None,
+            direction: None,
+        }
+    }
+
+    #[test]
+    fn relays_what_no_rule_matches() {
+        let filters = Filters::new(vec![rule("secrets", "hunter2", Action::Drop)]).unwrap();
+        let verdict = filters.apply(Direction::ToIrc, "#chan", "hello");
+        assert_eq!(verdict, Verdict::Relay("hello".to_owned()));
+        assert_eq!(
+            Filters::default().apply(Direction::ToSlack, "#chan", "hunter2"),
+            Verdict::Relay("hunter2".to_owned())
+        );
+    }
+
+    #[test]
+    fn rewrites_then_stops_at_the_first_drop() {
+        let mut replace = rule("tokens", "token=(\\w+)", Action::Replace);
+        replace.replacement = Some("token=<$1>".to_owned());
+        let filters = Filters::new(vec![
+            rule("passwords", "password \\S+", Action::Redact),
+            replace,
+            rule("spam", "buy now", Action::Drop),
+            rule("rude", "darn", Action::Warn),
+        ]).unwrap();
+
+        let verdict = filters.apply(Direction::ToIrc, "#chan", "password abc, token=xyz");
+        assert_eq!(verdict, Verdict::Relay("[redacted] token=<xyz>".to_owned()));
+        let verdict = filters.apply(Direction::ToIrc, "#chan", "darn, buy now");
+        assert_eq!(verdict, Verdict::Drop("spam".to_owned()));
+        let verdict = filters.apply(Direction::ToIrc, "#chan", "darn it");
+        assert_eq!(verdict, Verdict::Warn("rude".to_owned()));
+    }
+
+    #[test]
+    fn rules_apply_to_their_channels_and_direction() {
+        let mut to_irc = rule("to_irc", "x", Action::Drop);
+        to_irc.direction = Some(Scope::ToIrc);
+        to_irc.channels = Some(vec!["#Ops".to_owned()]);
+        let filters = Filters::new(vec![to_irc]).unwrap();
+
+        let verdict = filters.apply(Direction::ToIrc, "#ops", "x");
+        assert_eq!(verdict, Verdict::Drop("to_irc".to_owned()));
+        let verdict = filters.apply(Direction::ToSlack, "#ops", "x");
+        assert_eq!(verdict, Verdict::Relay("x".to_owned()));
+        let verdict = filters.apply(Direction::ToIrc, "#other", "x");
+        assert_eq!(verdict, Verdict::Relay("x".to_owned()));
+        // a direct message to someone called #ops is no different from any other
+        let verdict = filters.apply_direct(Direction::ToIrc, "x");
+        assert_eq!(verdict, Verdict::Drop("to_irc".to_owned()));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(Filters::new(vec![rule("broken", "(", Action::Drop)]).is_err());
+    }
+}
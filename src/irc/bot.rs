@@ -0,0 +1,288 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use aatxe_irc::client::ext::ClientExt;
+use aatxe_irc::client::{Client, IrcClient};
+use aatxe_irc::error::IrcError;
+use aatxe_irc::proto::command::Command as AatxeCmd;
+use aatxe_irc::proto::message::Message as AatxeMsg;
+use aatxe_irc::proto::response::Response;
+
+use commands::{self, Command, SlackRoster, HELP};
+use filters::Filters;
+use message::{PrivMsg, SlackMsg};
+
+use super::{is_channel, SlackOut};
+use super::members::Members;
+
+// Who a command came from, and so where the answer goes.
+#[derive(Clone)]
+enum Requester {
+    // a channel or, for private messages, a nick
+    Irc(String),
+    // an IRC channel if asked in a mapped channel, otherwise a private message
+    Slack { chan: String, user: String },
+}
+
+/// Answers commands from either side.
+pub struct Bot {
+    roster: SlackRoster,
+    members: Rc<Members>,
+    filters: Filters,
+    // IRC channel topics by lower case channel
+    topics: RefCell<HashMap<String, String>>,
+    // pending WHOIS replies by lower case nick
+    whois: RefCell<HashMap<String, (Requester, Vec<String>)>>,
+    // the mapped IRC channels
+    channels: Vec<String>,
+    server: String,
+    slack: SlackOut,
+}
+
+impl Bot {
+    pub fn new(
+        roster: SlackRoster,
+        members: Rc<Members>,
+        filters: Filters,
+        channels: Vec<String>,
+        server: String,
+        slack: SlackOut,
+    ) -> Bot {
+        Bot {
+            roster: roster,
+            members: members,
+            filters: filters,
+            topics: RefCell::new(HashMap::new()),
+            whois: RefCell::new(HashMap::new()),
+            channels: channels,
+            server: server,
+            slack: slack,
+        }
+    }
+
+    fn reply(&self, client: &IrcClient, to: &Requester, text: String) {
+        match *to {
+            Requester::Irc(ref target) => {
+                if let Err(e) = client.send_notice(target, &text) {
+                    warn!("failed to answer command on IRC: {:?}", e);
+                }
+            }
+            Requester::Slack { ref chan, ref user } => {
+                let msg = PrivMsg {
+                    chan: if chan.is_empty() { user.clone() } else { chan.clone() },
+                    msg: text,
+                    ..Default::default()
+                };
+                let msg = if chan.is_empty() {
+                    SlackMsg::DirectMsg(msg)
+                } else {
+                    SlackMsg::StatusMsg(msg)
+                };
+                self.slack.send(msg);
+            }
+        }
+    }
+
+    // The channels a command is about: the one it was asked in, or all of them.
+    fn channels_for(&self, chan: &str) -> Vec<String> {
+        if is_channel(chan) {
+            vec![chan.to_owned()]
+        } else {
+            self.channels.clone()
+        }
+    }
+
+    /// Keeps track of topics and WHOIS replies, and answers commands from IRC.
+    /// Returns true if the message was for the bot and shouldn't be relayed.
+    pub fn handle(&self, client: &IrcClient, msg: &AatxeMsg) -> bool {
+        match msg.command {
+            AatxeCmd::PRIVMSG(ref target, ref text) => {
+                let cmd = match Command::parse(text) {
+                    Some(c) => c,
+                    None => return false,
+                };
+                let from = match msg.source_nickname() {
+                    Some(n) => n,
+                    None => return false,
+                };
+                let to = if is_channel(target) { target } else { from };
+                self.answer_irc(client, cmd, Requester::Irc(to.to_owned()), target);
+                true
+            }
+            AatxeCmd::TOPIC(ref chan, Some(ref topic)) => {
+                self.set_topic(chan, topic);
+                false
+            }
+            AatxeCmd::Response(Response::RPL_TOPIC, ref args, Some(ref topic)) => {
+                if let Some(chan) = args.get(1) {
+                    self.set_topic(chan, topic);
+                }
+                false
+            }
+            AatxeCmd::Response(Response::RPL_NOTOPIC, ref args, _) => {
+                if let Some(chan) = args.get(1) {
+                    self.topics.borrow_mut().remove(&chan.to_lowercase());
+                }
+                false
+            }
+            AatxeCmd::Response(ref resp, ref args, ref suffix) => {
+                self.handle_whois(client, resp, args, suffix.as_ref())
+            }
+            _ => false,
+        }
+    }
+
+    fn set_topic(&self, chan: &str, topic: &str) {
+        self.topics
+            .borrow_mut()
+            .insert(chan.to_lowercase(), topic.to_owned());
+    }
+
+    fn handle_whois(
+        &self,
+        client: &IrcClient,
+        resp: &Response,
+        args: &[String],
+        suffix: Option<&String>,
+    ) -> bool {
+        let nick = match args.get(1) {
+            Some(n) => n.to_lowercase(),
+            None => return false,
+        };
+        let mut pending = self.whois.borrow_mut();
+        let line = match *resp {
+            Response::RPL_WHOISUSER => {
+                let user = args.get(2).map(|s| s.as_str()).unwrap_or("?");
+                let host = args.get(3).map(|s| s.as_str()).unwrap_or("?");
+                let real = suffix.map(|s| s.as_str()).unwrap_or("");
+                format!("{} is {}@{} ({})", args[1], user, host, real)
+            }
+            Response::RPL_WHOISSERVER => {
+                let server = args.get(2).map(|s| s.as_str()).unwrap_or("?");
+                format!("connected to {}", server)
+            }
+            Response::RPL_WHOISCHANNELS => {
+                format!("on {}", suffix.map(|s| s.as_str()).unwrap_or(""))
+            }
+            Response::RPL_WHOISIDLE => {
+                let idle = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(0);
+                format!("idle for {}", commands::format_ago(Duration::from_secs(idle)))
+            }
+            Response::ERR_NOSUCHNICK | Response::RPL_ENDOFWHOIS => {
+                let done = pending.remove(&nick);
+                drop(pending);
+                return match done {
+                    Some((to, lines)) => {
+                        let text = if lines.is_empty() {
+                            format!("{} isn't on IRC", args[1])
+                        } else {
+                            lines.join(", ")
+                        };
+                        self.reply(client, &to, text);
+                        true
+                    }
+                    None => false,
+                };
+            }
+            _ => return false,
+        };
+        match pending.get_mut(&nick) {
+            Some(&mut (_, ref mut lines)) => {
+                lines.push(line);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Commands from IRC are about slack.
+    fn answer_irc(&self, client: &IrcClient, cmd: Command, to: Requester, chan: &str) {
+        let text = match cmd {
+            Command::Names => self.channels_for(chan)
+                .iter()
+                .map(|c| {
+                    let active = self.roster.active(c);
+                    if active.is_empty() {
+                        format!("nobody has been active on slack in {} lately", c)
+                    } else {
+                        format!("active on slack in {}: {}", c, active.join(", "))
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("; "),
+            Command::Whois(nick) => match self.roster.last_seen(&nick) {
+                Some((c, ago)) => format!(
+                    "{} last spoke on slack in {} {} ago",
+                    nick,
+                    c,
+                    commands::format_ago(ago)
+                ),
+                None => format!("{} hasn't spoken on slack lately", nick),
+            },
+            Command::Topic => self.channels_for(chan)
+                .iter()
+                .map(|c| match self.roster.topic(c) {
+                    Some(t) => format!("slack topic of {}: {}", c, t),
+                    None => format!("the slack channel of {} has no topic", c),
+                })
+                .collect::<Vec<_>>()
+                .join("; "),
+            Command::Status => format!(
+                "relaying {} channels, slack is {}",
+                self.channels.len(),
+                if self.roster.connected() { "connected" } else { "disconnected" }
+            ),
+            Command::Filter(rule, sample) => self.filters.test(&rule, &sample),
+            Command::Usage(usage) => usage.to_owned(),
+            Command::Help => HELP.to_owned(),
+        };
+        self.reply(client, &to, text);
+    }
+
+    /// Answers a command from slack, which is about IRC.
+    pub fn answer_slack(&self, client: &IrcClient, msg: PrivMsg) -> Result<(), IrcError> {
+        let cmd = match Command::parse(&msg.msg) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let to = Requester::Slack {
+            chan: msg.chan.clone(),
+            user: msg.nick.clone(),
+        };
+        let text = match cmd {
+            Command::Names => self.channels_for(&msg.chan)
+                .iter()
+                .map(|c| format!("on IRC in {}: {}", c, self.members.list(c).join(", ")))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Command::Whois(nick) => {
+                // answered once the server is done replying
+                self.whois
+                    .borrow_mut()
+                    .insert(nick.to_lowercase(), (to, vec![]));
+                return client.send(AatxeCmd::WHOIS(None, nick));
+            }
+            Command::Topic => self.channels_for(&msg.chan)
+                .iter()
+                .map(|c| match self.topics.borrow().get(&c.to_lowercase()) {
+                    Some(t) => format!("IRC topic of {}: {}", c, t),
+                    None => format!("{} has no topic on IRC", c),
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Command::Status => format!(
+                "connected to {} as {}, relaying {} channels",
+                self.server,
+                client.current_nickname(),
+                self.channels.len()
+            ),
+            Command::Filter(rule, sample) => self.filters.test(&rule, &sample),
+            Command::Usage(usage) => usage.to_owned(),
+            Command::Help => HELP.to_owned(),
+        };
+        self.reply(client, &to, text);
+        Ok(())
+    }
+}
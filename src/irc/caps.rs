@@ -0,0 +1,549 @@
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::rc::Rc;
+
+use aatxe_irc::client::{Client, IrcClient};
+use aatxe_irc::error::IrcError;
+use aatxe_irc::proto::command::CapSubCommand;
+use aatxe_irc::proto::command::Command as AatxeCmd;
+use aatxe_irc::proto::message::{Message as AatxeMsg, Tag};
+use aatxe_irc::proto::response::Response;
+
+use journal::SharedJournal;
+use message::SlackMsg;
+
+use super::IrcFailure;
+use super::sasl::SaslCfg;
+
+pub const SERVER_TIME: &'static str = "server-time";
+pub const MESSAGE_TAGS: &'static str = "message-tags";
+pub const ECHO_MESSAGE: &'static str = "echo-message";
+pub const ACCOUNT_TAG: &'static str = "account-tag";
+pub const LABELED_RESPONSE: &'static str = "labeled-response";
+const SASL: &'static str = "sasl";
+
+// requested whenever the server offers them
+const WANTED: [&'static str; 5] = [
+    SERVER_TIME,
+    MESSAGE_TAGS,
+    ECHO_MESSAGE,
+    ACCOUNT_TAG,
+    LABELED_RESPONSE,
+];
+
+// how many sent messages may wait for their echo before the oldest is given up on
+const MAX_UNECHOED: usize = 256;
+
+/// The capabilities enabled on the current connection.
+#[derive(Clone, Default)]
+pub struct Caps(Rc<RefCell<HashSet<String>>>);
+
+impl Caps {
+    pub fn has(&self, cap: &str) -> bool {
+        self.0.borrow().contains(cap)
+    }
+
+    fn enable<'a, I: Iterator<Item = &'a str>>(&self, caps: I) {
+        self.0.borrow_mut().extend(caps.map(|c| c.to_owned()))
+    }
+
+    /// Forgets what was enabled, for a new connection.
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear()
+    }
+}
+
+// Without echo-message, messages are confirmed by the server answering a PING sent
+// after them, which it only does once it has processed everything before it.
+#[derive(Default)]
+struct Pings {
+    // messages sent since the last ping
+    sent: Vec<SlackMsg>,
+    // the ping waiting for its PONG, with the messages it confirms
+    in_flight: Option<(String, Vec<SlackMsg>)>,
+}
+
+// A message waiting for its echo.
+struct Unechoed {
+    target: String,
+    // set if the server echoes labels back
+    label: Option<String>,
+    msg: SlackMsg,
+}
+
+#[derive(Default)]
+struct Unconfirmed {
+    pending: VecDeque<Unechoed>,
+    pings: Pings,
+    // for PING tokens and labels
+    count: u64,
+}
+
+impl Unconfirmed {
+    fn token(&mut self) -> String {
+        self.count += 1;
+        format!("slagw-{}", self.count)
+    }
+}
+
+/// Messages sent to IRC that the server hasn't confirmed yet. With
+/// echo-message, a message only counts as delivered once it's been echoed,
+/// otherwise once a later PING is answered. They outlive the connection, so
+/// that what wasn't confirmed before a disconnection can be sent again.
+#[derive(Clone)]
+pub struct Echoes {
+    caps: Caps,
+    state: Rc<RefCell<Unconfirmed>>,
+}
+
+impl Echoes {
+    pub fn new(caps: Caps) -> Echoes {
+        Echoes {
+            caps: caps,
+            state: Rc::new(RefCell::new(Unconfirmed::default())),
+        }
+    }
+
+    /// Takes note of a message about to be sent as `cmd`, labelling it if the
+    /// server supports labels. Returns what to send and whether it awaits
+    /// confirmation, which it doesn't unless it's in the journal.
+    pub fn sent(
+        &self,
+        cmd: AatxeCmd,
+        msg: SlackMsg,
+        journal: &SharedJournal,
+    ) -> (AatxeMsg, bool) {
+        let mut state = self.state.borrow_mut();
+        if msg.journal_id().is_none() {
+            return (cmd.into(), false);
+        }
+        if !self.caps.has(ECHO_MESSAGE) {
+            state.pings.sent.push(msg);
+            return (cmd.into(), true);
+        }
+        let target = match cmd {
+            AatxeCmd::PRIVMSG(ref t, _) | AatxeCmd::NOTICE(ref t, _) => t.clone(),
+            _ => return (cmd.into(), false),
+        };
+        if state.pending.len() >= MAX_UNECHOED {
+            if let Some(dropped) = state.pending.pop_front() {
+                warn!("message to {} was never echoed back by the IRC server", dropped.target);
+                journal.lock().unwrap().ack(dropped.msg.journal_id());
+            }
+        }
+        let label = if self.caps.has(LABELED_RESPONSE) {
+            Some(state.token())
+        } else {
+            None
+        };
+        let out = AatxeMsg {
+            tags: label.clone().map(|l| vec![Tag("label".to_owned(), Some(l))]),
+            prefix: None,
+            command: cmd,
+        };
+        state.pending.push_back(Unechoed {
+            target: target,
+            label: label,
+            msg: msg,
+        });
+        (out, true)
+    }
+
+    /// Sends a PING to confirm what was sent since the last one, unless a PING
+    /// is still waiting for its answer.
+    pub fn confirm(&self, client: &IrcClient) {
+        if let Some(token) = self.ping() {
+            if let Err(e) = client.send(AatxeCmd::PING(token, None)) {
+                error!("failed to send a PING to confirm delivery: {:?}", e);
+            }
+        }
+    }
+
+    // The token of the PING to send next, if one is to be sent.
+    fn ping(&self) -> Option<String> {
+        let mut guard = self.state.borrow_mut();
+        let state = &mut *guard;
+        if state.pings.in_flight.is_some() || state.pings.sent.is_empty() {
+            return None;
+        }
+        let token = state.token();
+        let sent = state.pings.sent.drain(..).collect();
+        state.pings.in_flight = Some((token.clone(), sent));
+        Some(token)
+    }
+
+    // Acknowledges what the answered PING confirms.
+    fn pong(&self, token: &str, journal: &SharedJournal) -> bool {
+        let confirmed = {
+            let mut state = self.state.borrow_mut();
+            match state.pings.in_flight.take() {
+                Some((ref t, ref msgs)) if t == token => msgs.clone(),
+                other => {
+                    state.pings.in_flight = other;
+                    return false;
+                }
+            }
+        };
+        debug!("IRC server confirmed delivery of {} messages", confirmed.len());
+        let mut journal = journal.lock().unwrap();
+        for msg in confirmed {
+            journal.ack(msg.journal_id());
+        }
+        true
+    }
+
+    /// Confirms the delivery of the message being echoed, if it's an echo, or
+    /// of the messages sent before a PING, if it's the PONG, in which case the
+    /// next PING goes out.
+    pub fn handle(&self, client: &IrcClient, msg: &AatxeMsg, journal: &SharedJournal) -> bool {
+        if let AatxeCmd::PONG(ref a, ref b) = msg.command {
+            let answered = self.pong(b.as_ref().unwrap_or(a), journal);
+            if answered {
+                self.confirm(client);
+            }
+            return answered;
+        }
+        self.echo(client.current_nickname(), msg, journal)
+    }
+
+    // Echoes are told apart by their label, or else by being the oldest sent to
+    // their target, since the server may have changed the text.
+    fn echo(&self, own_nick: &str, msg: &AatxeMsg, journal: &SharedJournal) -> bool {
+        if !self.caps.has(ECHO_MESSAGE) {
+            return false;
+        }
+        let target = match msg.command {
+            AatxeCmd::PRIVMSG(ref t, _) | AatxeCmd::NOTICE(ref t, _) => t,
+            _ => return false,
+        };
+        if msg.source_nickname() != Some(own_nick) {
+            return false;
+        }
+        let label = tag(msg, "label");
+        let mut state = self.state.borrow_mut();
+        let pos = state.pending.iter().position(|u| match label {
+            Some(ref l) => u.label.as_ref() == Some(l),
+            None => u.target.eq_ignore_ascii_case(target),
+        });
+        if let Some(echoed) = pos.and_then(|p| state.pending.remove(p)) {
+            let msgid = tag(msg, "msgid").unwrap_or_else(|| "no id".to_owned());
+            debug!("IRC server confirmed delivery to {} ({})", target, msgid);
+            journal.lock().unwrap().ack(echoed.msg.journal_id());
+        }
+        true
+    }
+
+    /// Gives up on confirmations from the connection that was lost, returning
+    /// the messages that didn't get one, oldest first.
+    pub fn take_unconfirmed(&self) -> Vec<SlackMsg> {
+        let mut guard = self.state.borrow_mut();
+        let state = &mut *guard;
+        let mut msgs = match state.pings.in_flight.take() {
+            Some((_, msgs)) => msgs,
+            None => vec![],
+        };
+        msgs.extend(state.pings.sent.drain(..));
+        msgs.extend(state.pending.drain(..).map(|u| u.msg));
+        msgs
+    }
+}
+
+/// Looks up the value of an IRCv3 message tag.
+pub fn tag(msg: &AatxeMsg, name: &str) -> Option<String> {
+    msg.tags
+        .as_ref()?
+        .iter()
+        .find(|t| t.0 == name)
+        .and_then(|t| t.1.clone())
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Listing,
+    Requested,
+    Authenticating,
+    Done,
+}
+
+/// Registers the connection, negotiating capabilities and authenticating via
+/// SASL first if it's configured.
+pub struct Registration {
+    sasl: Option<SaslCfg>,
+    state: State,
+    available: HashSet<String>,
+    caps: Caps,
+}
+
+impl Registration {
+    pub fn new(sasl: Option<SaslCfg>, caps: Caps) -> Registration {
+        Registration {
+            sasl: sasl,
+            state: State::Listing,
+            available: HashSet::new(),
+            caps: caps,
+        }
+    }
+
+    // Like identify(), except that registration is held until capability
+    // negotiation is done instead of ending it straight away.
+    pub fn start(&mut self, client: &IrcClient) -> Result<(), IrcFailure> {
+        let cfg = client.config();
+        client
+            .send(AatxeCmd::Raw("CAP".to_owned(), vec!["LS".to_owned(), "302".to_owned()], None))
+            .and_then(|_| {
+                if cfg.password() != "" {
+                    client.send(AatxeCmd::PASS(cfg.password().to_owned()))?;
+                }
+                client.send(AatxeCmd::NICK(cfg.nickname()?.to_owned()))?;
+                client.send(AatxeCmd::USER(
+                    cfg.username().to_owned(),
+                    "0".to_owned(),
+                    cfg.real_name().to_owned(),
+                ))
+            })
+            .map_err(IrcFailure::CantIdentify)
+    }
+
+    fn end(&mut self, client: &IrcClient) -> Result<(), IrcError> {
+        self.state = State::Done;
+        client.send(AatxeCmd::CAP(None, CapSubCommand::END, None, None))
+    }
+
+    // What's wanted out of what the server listed.
+    fn wanted(&self) -> Result<Vec<&'static str>, IrcFailure> {
+        if self.sasl.is_some() && !self.available.contains(SASL) {
+            return Err(IrcFailure::SaslFailed("server doesn't support SASL".to_owned()));
+        }
+        let mut wanted: Vec<&str> = WANTED
+            .iter()
+            .cloned()
+            .filter(|c| self.available.contains(*c))
+            .collect();
+        if self.sasl.is_some() {
+            wanted.push(SASL);
+        }
+        Ok(wanted)
+    }
+
+    fn request(&mut self, client: &IrcClient) -> Result<(), IrcFailure> {
+        let wanted = self.wanted()?;
+        if wanted.is_empty() {
+            return self.end(client).map_err(IrcFailure::CantIdentify);
+        }
+        self.state = State::Requested;
+        let req = AatxeCmd::Raw("CAP".to_owned(), vec!["REQ".to_owned()], Some(wanted.join(" ")));
+        client.send(req).map_err(IrcFailure::CantIdentify)
+    }
+
+    // Takes note of a line of CAP LS, returning whether more lines follow.
+    fn listed(&mut self, a: Option<&String>, b: Option<&String>) -> bool {
+        // multi-line replies have a "*" before the capabilities on all but the last line
+        let more = a.map(|a| a == "*").unwrap_or(false);
+        let listed = if more { b } else { b.or(a) };
+        for cap in listed.iter().flat_map(|l| l.split_whitespace()) {
+            // 302 style values, e.g. sasl=PLAIN,EXTERNAL
+            self.available.insert(cap.split('=').next().unwrap_or(cap).to_owned());
+        }
+        more
+    }
+
+    pub fn handle(&mut self, client: &IrcClient, msg: &AatxeMsg) -> Result<(), IrcFailure> {
+        if self.state == State::Done {
+            return Ok(());
+        }
+        let res = match msg.command {
+            AatxeCmd::CAP(_, CapSubCommand::LS, ref a, ref b) if self.state == State::Listing => {
+                if self.listed(a.as_ref(), b.as_ref()) {
+                    return Ok(());
+                }
+                return self.request(client);
+            }
+            AatxeCmd::CAP(_, CapSubCommand::ACK, ref a, ref b) => {
+                let acked: Vec<&str> = a.iter()
+                    .chain(b.iter())
+                    .flat_map(|l| l.split_whitespace())
+                    .collect();
+                info!("enabled IRC capabilities: {}", acked.join(" "));
+                self.caps.enable(acked.iter().cloned());
+                match self.sasl {
+                    Some(ref sasl) if acked.contains(&SASL) => {
+                        self.state = State::Authenticating;
+                        sasl.start(client)
+                    }
+                    _ => self.end(client),
+                }
+            }
+            AatxeCmd::CAP(_, CapSubCommand::NAK, _, _) => {
+                if self.sasl.is_some() {
+                    return Err(IrcFailure::SaslFailed("server refused SASL".to_owned()));
+                }
+                warn!("server refused IRC capabilities");
+                self.end(client)
+            }
+            AatxeCmd::AUTHENTICATE(ref challenge) if challenge == "+" => match self.sasl {
+                Some(ref sasl) => sasl.respond(client),
+                None => Ok(()),
+            },
+            AatxeCmd::Response(Response::RPL_LOGGEDIN, ref args, _) => {
+                let account = args.get(2).map(|s| s.as_str()).unwrap_or("?");
+                info!("authenticated to IRC as {}", account);
+                Ok(())
+            }
+            AatxeCmd::Response(Response::RPL_SASLSUCCESS, _, _) => self.end(client),
+            AatxeCmd::Response(Response::ERR_SASLFAIL, _, ref reason)
+            | AatxeCmd::Response(Response::ERR_SASLTOOLONG, _, ref reason)
+            | AatxeCmd::Response(Response::ERR_SASLABORTED, _, ref reason)
+            | AatxeCmd::Response(Response::ERR_NICKLOCKED, _, ref reason) => {
+                let reason = reason.clone().unwrap_or_else(|| "authentication failed".to_owned());
+                return Err(IrcFailure::SaslFailed(reason));
+            }
+            // servers without capability support just go ahead with registration
+            AatxeCmd::Response(Response::RPL_WELCOME, _, _) => {
+                self.state = State::Done;
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        res.map_err(IrcFailure::CantIdentify)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use journal::Direction;
+    use journal::tests::{msg, TempDir};
+
+    use super::*;
+
+    fn echoes(caps: &[&str]) -> Echoes {
+        let enabled = Caps::default();
+        enabled.enable(caps.iter().cloned());
+        Echoes::new(enabled)
+    }
+
+    // Records and sends messages to #chan, returning whether each awaits confirmation.
+    fn send(echoes: &Echoes, journal: &SharedJournal, texts: &[&str]) -> Vec<bool> {
+        texts
+            .iter()
+            .map(|text| {
+                let mut m = msg(text);
+                journal.lock().unwrap().record(Direction::ToIrc, &mut m);
+                let cmd = AatxeCmd::PRIVMSG("#chan".to_owned(), text.to_string());
+                echoes.sent(cmd, m, journal).1
+            })
+            .collect()
+    }
+
+    fn pending(journal: &SharedJournal) -> Vec<String> {
+        journal
+            .lock()
+            .unwrap()
+            .pending(Direction::ToIrc)
+            .into_iter()
+            .map(|(_, m)| m.priv_msg().msg.clone())
+            .collect()
+    }
+
+    fn parse(line: &str) -> AatxeMsg {
+        line.parse().unwrap()
+    }
+
+    #[test]
+    fn confirms_by_label() {
+        let dir = TempDir::new("label");
+        let journal = dir.journal(None).shared();
+        let echoes = echoes(&[ECHO_MESSAGE, LABELED_RESPONSE]);
+        assert_eq!(send(&echoes, &journal, &["one", "two"]), vec![true, true]);
+
+        let echo = parse("@label=slagw-2 :relay!r@host PRIVMSG #chan :two, edited");
+        assert!(echoes.echo("relay", &echo, &journal));
+        assert_eq!(pending(&journal), vec!["one"]);
+        // someone else's message, even with a matching label, confirms nothing
+        let other = parse("@label=slagw-1 :alice!a@host PRIVMSG #chan :one");
+        assert!(!echoes.echo("relay", &other, &journal));
+        assert_eq!(pending(&journal), vec!["one"]);
+    }
+
+    #[test]
+    fn confirms_the_oldest_to_the_same_target() {
+        let dir = TempDir::new("target");
+        let journal = dir.journal(None).shared();
+        let echoes = echoes(&[ECHO_MESSAGE]);
+        send(&echoes, &journal, &["one", "two"]);
+        let cmd = AatxeCmd::PRIVMSG("#other".to_owned(), "three".to_owned());
+        let mut m = msg("three");
+        journal.lock().unwrap().record(Direction::ToIrc, &mut m);
+        echoes.sent(cmd, m, &journal);
+
+        assert!(echoes.echo("relay", &parse(":relay!r@host PRIVMSG #OTHER :3"), &journal));
+        assert_eq!(pending(&journal), vec!["one", "two"]);
+        assert!(echoes.echo("relay", &parse(":relay!r@host PRIVMSG #chan :2"), &journal));
+        assert_eq!(pending(&journal), vec!["two"]);
+        let unconfirmed: Vec<_> = echoes
+            .take_unconfirmed()
+            .into_iter()
+            .map(|m| m.priv_msg().msg.clone())
+            .collect();
+        assert_eq!(unconfirmed, vec!["two"]);
+    }
+
+    #[test]
+    fn confirms_by_ping_token() {
+        let dir = TempDir::new("ping");
+        let journal = dir.journal(None).shared();
+        let echoes = echoes(&[]);
+        assert_eq!(echoes.ping(), None);
+        send(&echoes, &journal, &["one", "two"]);
+        assert_eq!(echoes.ping(), Some("slagw-1".to_owned()));
+        send(&echoes, &journal, &["three"]);
+        // one PING at a time
+        assert_eq!(echoes.ping(), None);
+
+        assert!(!echoes.pong("slagw-0", &journal));
+        assert_eq!(pending(&journal), vec!["one", "two", "three"]);
+        assert!(echoes.pong("slagw-1", &journal));
+        assert_eq!(pending(&journal), vec!["three"]);
+        assert_eq!(echoes.ping(), Some("slagw-2".to_owned()));
+        assert!(echoes.pong("slagw-2", &journal));
+        assert!(pending(&journal).is_empty());
+        assert!(echoes.take_unconfirmed().is_empty());
+    }
+
+    #[test]
+    fn gives_up_on_the_oldest_when_too_many_are_unechoed() {
+        let dir = TempDir::new("unechoed");
+        let journal = dir.journal(None).shared();
+        let echoes = echoes(&[ECHO_MESSAGE]);
+        let texts: Vec<String> = (0..MAX_UNECHOED + 1).map(|i| i.to_string()).collect();
+        let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+        send(&echoes, &journal, &texts);
+        let left = pending(&journal);
+        assert_eq!(left.len(), MAX_UNECHOED);
+        assert_eq!(left[0], "1");
+        assert_eq!(echoes.take_unconfirmed().len(), MAX_UNECHOED);
+    }
+
+    #[test]
+    fn lists_caps_over_several_lines() {
+        let mut reg = Registration::new(None, Caps::default());
+        let lines = [
+            ":irc.test CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL echo-message",
+            ":irc.test CAP * LS :server-time labeled-response",
+        ];
+        let more: Vec<bool> = lines
+            .iter()
+            .map(|line| match parse(line).command {
+                AatxeCmd::CAP(_, CapSubCommand::LS, ref a, ref b) => {
+                    reg.listed(a.as_ref(), b.as_ref())
+                }
+                ref other => panic!("not CAP LS: {:?}", other),
+            })
+            .collect();
+        assert_eq!(more, vec![true, false]);
+        assert!(reg.available.contains(SASL));
+        assert_eq!(
+            reg.wanted().unwrap(),
+            vec![SERVER_TIME, ECHO_MESSAGE, LABELED_RESPONSE]
+        );
+    }
+}
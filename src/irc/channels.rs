@@ -0,0 +1,227 @@
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::Future;
+use futures::future::Shared;
+use futures::sync::oneshot;
+use tokio_core::reactor;
+use tokio_timer::Timer;
+
+use aatxe_irc::client::{Client, IrcClient};
+use aatxe_irc::proto::command::Command as AatxeCmd;
+use aatxe_irc::proto::message::Message as AatxeMsg;
+use aatxe_irc::proto::response::Response;
+
+use super::join_channels;
+
+const REJOIN_BASE_DELAY: u64 = 5;
+const REJOIN_MAX_DELAY: u64 = 300;
+
+/// Rejoins channels after being kicked, backing off if rejoining keeps failing.
+/// Gives up on channels the relay is banned from or has the wrong key for.
+pub struct ChannelGuard {
+    keys: HashMap<String, String>,
+    // channels that are being rejoined, with the number of attempts so far
+    rejoining: Rc<RefCell<HashMap<String, u32>>>,
+    timer: Timer,
+    handle: reactor::Handle,
+    // pending rejoins stop once the guard is dropped along with the connection
+    _guard: oneshot::Sender<()>,
+    stopped: Shared<oneshot::Receiver<()>>,
+}
+
+impl ChannelGuard {
+    pub fn new(
+        keys: HashMap<String, String>,
+        timer: Timer,
+        handle: reactor::Handle,
+    ) -> ChannelGuard {
+        let (guard, stopped) = oneshot::channel();
+        ChannelGuard {
+            keys: keys,
+            rejoining: Rc::new(RefCell::new(HashMap::new())),
+            timer: timer,
+            handle: handle,
+            _guard: guard,
+            stopped: stopped.shared(),
+        }
+    }
+
+    pub fn handle(&self, client: &IrcClient, msg: &AatxeMsg) {
+        let own_nick = client.current_nickname();
+        match msg.command {
+            AatxeCmd::KICK(ref chans, ref users, _) => {
+                let kicked = users.split(',').any(|u| u.eq_ignore_ascii_case(own_nick));
+                if kicked {
+                    for chan in chans.split(',') {
+                        self.schedule_rejoin(client, chan);
+                    }
+                }
+            }
+            AatxeCmd::JOIN(ref chans, _, _) if msg.source_nickname() == Some(own_nick) => {
+                let mut rejoining = self.rejoining.borrow_mut();
+                for chan in chans.split(',') {
+                    if rejoining.remove(chan).is_some() {
+                        info!("rejoined {}", chan);
+                    }
+                }
+            }
+            // retrying won't help until someone lifts the ban or fixes the key
+            AatxeCmd::Response(Response::ERR_INVITEONLYCHAN, ref args, _)
+            | AatxeCmd::Response(Response::ERR_BANNEDFROMCHAN, ref args, _)
+            | AatxeCmd::Response(Response::ERR_BADCHANNELKEY, ref args, _) => {
+                if let Some(chan) = args.get(1) {
+                    if self.rejoining.borrow_mut().remove(chan).is_some() {
+                        warn!("giving up on rejoining {}", chan);
+                    }
+                }
+            }
+            AatxeCmd::Response(Response::ERR_CHANNELISFULL, ref args, _) => {
+                let chan = match args.get(1) {
+                    Some(c) => c,
+                    None => return,
+                };
+                if self.rejoining.borrow().contains_key(chan) {
+                    self.schedule_rejoin(client, chan);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn schedule_rejoin(&self, client: &IrcClient, chan: &str) {
+        let attempt = {
+            let mut rejoining = self.rejoining.borrow_mut();
+            let attempt = rejoining.entry(chan.to_owned()).or_insert(0);
+            *attempt += 1;
+            *attempt
+        };
+        let delay = cmp::min(
+            REJOIN_BASE_DELAY.saturating_mul(1 << cmp::min(attempt - 1, 16)),
+            REJOIN_MAX_DELAY,
+        );
+        warn!("rejoining {} in {}s", chan, delay);
+
+        let client = client.clone();
+        let chans = vec![(chan.to_owned(), self.keys.get(chan).cloned())];
+        let rejoin = self.timer
+            .sleep(Duration::from_secs(delay))
+            .map_err(|e| error!("rejoin timer failed: {}", e))
+            .and_then(move |_| {
+                join_channels(&client, &chans).map_err(|e| error!("failed to rejoin: {:?}", e))
+            })
+            .select2(self.stopped.clone())
+            .then(|_| Ok(()));
+        self.handle.spawn(rejoin);
+    }
+}
+
+/// Tells which channels the relay is done joining, whether it got into them or
+/// not, so that nothing is sent to a channel before it's in there.
+pub struct Joining {
+    // lower case channels yet to be joined
+    waiting: RefCell<HashSet<String>>,
+    registered: Cell<bool>,
+}
+
+impl Joining {
+    pub fn new<'a, I: Iterator<Item = &'a String>>(channels: I) -> Joining {
+        Joining {
+            waiting: RefCell::new(channels.map(|c| c.to_lowercase()).collect()),
+            registered: Cell::new(false),
+        }
+    }
+
+    /// Whether messages can be sent to a target: once registered, to anything but
+    /// the channels still being joined.
+    pub fn is_ready(&self, target: &str) -> bool {
+        self.registered.get() && !self.waiting.borrow().contains(&target.to_lowercase())
+    }
+
+    /// Returns true when more targets became ready, i.e. a channel has been joined
+    /// or failed to, or the relay has registered.
+    pub fn handle(&self, own_nick: &str, msg: &AatxeMsg) -> bool {
+        let mut waiting = self.waiting.borrow_mut();
+        match msg.command {
+            AatxeCmd::JOIN(ref chans, _, _) if msg.source_nickname() == Some(own_nick) => chans
+                .split(',')
+                .fold(false, |any, chan| waiting.remove(&chan.to_lowercase()) || any),
+            AatxeCmd::Response(Response::RPL_WELCOME, _, _) => !self.registered.replace(true),
+            // whatever the reason a channel can't be joined, e.g. ERR_NEEDREGGEDNICK, it's
+            // a 4xx reply with the channel as its second argument
+            AatxeCmd::Response(resp, ref args, _) if resp as u16 / 100 == 4 => {
+                args.get(1).map(|c| waiting.remove(&c.to_lowercase())).unwrap_or(false)
+            }
+            // numerics the IRC library doesn't know about
+            AatxeCmd::Raw(ref code, ref args, _) if is_error_numeric(code) => {
+                args.get(1).map(|c| waiting.remove(&c.to_lowercase())).unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops waiting for the channels that haven't been joined yet, returning them.
+    pub fn give_up(&self) -> Vec<String> {
+        self.waiting.borrow_mut().drain().collect()
+    }
+}
+
+fn is_error_numeric(code: &str) -> bool {
+    code.len() == 3 && code.starts_with('4') && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joining() -> Joining {
+        let chans = vec!["#Rust".to_owned(), "#slack".to_owned(), "#ops".to_owned()];
+        Joining::new(chans.iter())
+    }
+
+    fn handle(joining: &Joining, line: &str) -> bool {
+        joining.handle("relay", &line.parse().unwrap())
+    }
+
+    #[test]
+    fn channels_are_ready_one_by_one() {
+        let joining = joining();
+        assert!(!joining.is_ready("alice"));
+        assert!(handle(&joining, ":irc.test 001 relay :Welcome"));
+        assert!(joining.is_ready("alice"));
+        assert!(!joining.is_ready("#rust"));
+
+        assert!(!handle(&joining, ":alice!a@h JOIN #rust"));
+        assert!(handle(&joining, ":relay!r@h JOIN #rust"));
+        assert!(joining.is_ready("#RUST"));
+        assert!(!joining.is_ready("#slack"));
+        assert!(!handle(&joining, ":relay!r@h JOIN #rust"));
+    }
+
+    #[test]
+    fn failing_to_join_is_done_joining() {
+        let joining = joining();
+        handle(&joining, ":irc.test 001 relay :Welcome");
+        assert!(handle(&joining, ":irc.test 477 relay #slack :Cannot join channel (+r)"));
+        assert!(joining.is_ready("#slack"));
+        assert!(handle(&joining, ":irc.test 474 relay #ops :Cannot join channel (+b)"));
+        assert!(joining.is_ready("#ops"));
+        // not about a channel being joined
+        assert!(!handle(&joining, ":irc.test 433 relay relay_ :Nickname is already in use"));
+        assert!(!joining.is_ready("#rust"));
+    }
+
+    #[test]
+    fn gives_up_on_the_rest() {
+        let joining = joining();
+        handle(&joining, ":irc.test 001 relay :Welcome");
+        handle(&joining, ":relay!r@h JOIN #slack");
+        let mut late = joining.give_up();
+        late.sort();
+        assert_eq!(late, vec!["#ops", "#rust"]);
+        assert!(joining.is_ready("#rust"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use chrono::Local;
+
+use aatxe_irc::client::{Client, IrcClient};
+use aatxe_irc::proto::command::Command as AatxeCmd;
+use aatxe_irc::proto::message::Message as AatxeMsg;
+
+use super::IrcFailure;
+
+const DELIM: char = '\x01';
+
+/// Splits a CTCP message into its command and arguments, or returns None if
+/// it's a plain message.
+pub fn parse(text: &str) -> Option<(&str, &str)> {
+    if !text.starts_with(DELIM) {
+        return None;
+    }
+    let inner = text[1..].trim_right_matches(DELIM);
+    let mut parts = inner.splitn(2, ' ');
+    let command = parts.next().unwrap_or("");
+    Some((command, parts.next().unwrap_or("")))
+}
+
+/// Answers CTCP queries sent directly to the relay.
+pub struct Ctcp {
+    // configured replies by upper case command, an empty reply disables one
+    replies: HashMap<String, String>,
+}
+
+impl Ctcp {
+    pub fn new(configured: Option<HashMap<String, String>>) -> Ctcp {
+        let mut replies = HashMap::new();
+        replies.insert(
+            "VERSION".to_owned(),
+            format!("slag {}", env!("CARGO_PKG_VERSION")),
+        );
+        for (command, reply) in configured.unwrap_or_default() {
+            replies.insert(command.to_uppercase(), reply);
+        }
+        Ctcp { replies: replies }
+    }
+
+    fn reply(&self, command: &str, args: &str) -> Option<String> {
+        let configured = self.replies.get(command);
+        if configured.map(|r| r.is_empty()).unwrap_or(false) {
+            return None;
+        }
+        match (command, configured) {
+            (_, Some(reply)) => Some(reply.clone()),
+            ("PING", None) => Some(args.to_owned()),
+            ("TIME", None) => Some(Local::now().to_rfc2822()),
+            ("CLIENTINFO", None) => {
+                let mut known: Vec<&str> = vec!["ACTION", "CLIENTINFO", "PING", "TIME"];
+                known.extend(
+                    self.replies
+                        .iter()
+                        .filter(|&(_, r)| !r.is_empty())
+                        .map(|(c, _)| c.as_str()),
+                );
+                known.sort();
+                known.dedup();
+                Some(known.join(" "))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn handle(&self, client: &IrcClient, msg: &AatxeMsg) -> Result<(), IrcFailure> {
+        let (target, text) = match msg.command {
+            AatxeCmd::PRIVMSG(ref t, ref m) => (t, m),
+            _ => return Ok(()),
+        };
+        // queries in channels aren't answered
+        if !target.eq_ignore_ascii_case(client.current_nickname()) {
+            return Ok(());
+        }
+        let (command, args) = match parse(text) {
+            Some(q) => q,
+            None => return Ok(()),
+        };
+        let source = match msg.source_nickname() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let command = command.to_uppercase();
+        match self.reply(&command, args) {
+            Some(reply) => {
+                debug!("answering CTCP {} from {}", command, source);
+                let text = format!("{}{} {}{}", DELIM, command, reply, DELIM);
+                client
+                    .send(AatxeCmd::NOTICE(source.to_owned(), text))
+                    .map_err(IrcFailure::Connection)
+            }
+            None => Ok(()),
+        }
+    }
+}
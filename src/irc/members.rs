@@ -0,0 +1,355 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use aatxe_irc::proto::command::Command as AatxeCmd;
+use aatxe_irc::proto::message::Message as AatxeMsg;
+use aatxe_irc::proto::mode::{ChannelMode, Mode};
+use aatxe_irc::proto::response::Response;
+
+// channel membership prefixes, highest first
+const PREFIXES: &'static str = "~&@%+";
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PresenceCfg {
+    /// Seconds between updates of the presence view, defaults to 300.
+    interval: Option<u64>,
+}
+
+const DEFAULT_PRESENCE_INTERVAL: u64 = 300;
+
+impl PresenceCfg {
+    pub fn interval(&self) -> u64 {
+        self.interval.unwrap_or(DEFAULT_PRESENCE_INTERVAL)
+    }
+}
+
+struct Member {
+    nick: String,
+    // membership prefixes the member has, highest first
+    prefixes: String,
+}
+
+impl Member {
+    fn parse(name: &str) -> Member {
+        let nick = name.trim_left_matches(|c: char| PREFIXES.contains(c));
+        let mut member = Member {
+            nick: nick.to_owned(),
+            prefixes: String::new(),
+        };
+        for p in name[..name.len() - nick.len()].chars() {
+            member.set(p, true);
+        }
+        member
+    }
+
+    fn set(&mut self, prefix: char, on: bool) {
+        let mut prefixes: Vec<char> = self.prefixes.chars().filter(|&c| c != prefix).collect();
+        if on {
+            prefixes.push(prefix);
+        }
+        prefixes.sort_by_key(|&c| PREFIXES.find(c));
+        self.prefixes = prefixes.into_iter().collect();
+    }
+
+    fn rank(&self) -> usize {
+        self.prefixes
+            .chars()
+            .next()
+            .and_then(|c| PREFIXES.find(c))
+            .unwrap_or(PREFIXES.len())
+    }
+
+    fn display(&self) -> String {
+        match self.prefixes.chars().next() {
+            Some(p) => format!("{}{}", p, self.nick),
+            None => self.nick.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Channels {
+    // members by lower case channel and nick
+    members: HashMap<String, HashMap<String, Member>>,
+    // NAMES replies that haven't ended yet
+    names: HashMap<String, HashMap<String, Member>>,
+    // channels whose members changed since the presence view was last updated
+    changed: HashSet<String>,
+}
+
+impl Channels {
+    fn join(&mut self, chan: &str, nick: &str) {
+        let chan = chan.to_lowercase();
+        self.members
+            .entry(chan.clone())
+            .or_insert_with(HashMap::new)
+            .insert(nick.to_lowercase(), Member::parse(nick));
+        self.changed.insert(chan);
+    }
+
+    fn part(&mut self, chan: &str, nick: &str) {
+        let chan = chan.to_lowercase();
+        let left = self.members
+            .get_mut(&chan)
+            .and_then(|m| m.remove(&nick.to_lowercase()))
+            .is_some();
+        if left {
+            self.changed.insert(chan);
+        }
+    }
+
+    fn quit(&mut self, nick: &str) {
+        let nick = nick.to_lowercase();
+        for (chan, members) in &mut self.members {
+            if members.remove(&nick).is_some() {
+                self.changed.insert(chan.clone());
+            }
+        }
+    }
+
+    fn rename(&mut self, old: &str, new: &str) {
+        let old = old.to_lowercase();
+        for (chan, members) in &mut self.members {
+            if let Some(mut member) = members.remove(&old) {
+                member.nick = new.to_owned();
+                members.insert(new.to_lowercase(), member);
+                self.changed.insert(chan.clone());
+            }
+        }
+    }
+
+    fn set_prefix(&mut self, chan: &str, nick: &str, prefix: char, on: bool) {
+        let chan = chan.to_lowercase();
+        let member = self.members
+            .get_mut(&chan)
+            .and_then(|m| m.get_mut(&nick.to_lowercase()));
+        if let Some(member) = member {
+            member.set(prefix, on);
+            self.changed.insert(chan);
+        }
+    }
+}
+
+/// Who is in each IRC channel the relay is in, kept up to date from NAMES
+/// replies, joins, parts, quits, nick and mode changes.
+#[derive(Default)]
+pub struct Members(RefCell<Channels>);
+
+impl Members {
+    pub fn handle(&self, own_nick: &str, msg: &AatxeMsg) {
+        let mut channels = self.0.borrow_mut();
+        let source = msg.source_nickname().unwrap_or("");
+        match msg.command {
+            AatxeCmd::JOIN(ref chans, _, _) => for chan in chans.split(',') {
+                // a fresh NAMES reply follows our own join
+                if source == own_nick {
+                    channels.members.remove(&chan.to_lowercase());
+                }
+                channels.join(chan, source);
+            },
+            AatxeCmd::PART(ref chans, _) => for chan in chans.split(',') {
+                if source == own_nick {
+                    channels.members.remove(&chan.to_lowercase());
+                } else {
+                    channels.part(chan, source);
+                }
+            },
+            AatxeCmd::KICK(ref chans, ref users, _) => for chan in chans.split(',') {
+                for user in users.split(',') {
+                    if user.eq_ignore_ascii_case(own_nick) {
+                        channels.members.remove(&chan.to_lowercase());
+                    } else {
+                        channels.part(chan, user);
+                    }
+                }
+            },
+            AatxeCmd::QUIT(_) => channels.quit(source),
+            AatxeCmd::NICK(ref new) => channels.rename(source, new),
+            AatxeCmd::ChannelMODE(ref chan, ref modes) => for mode in modes {
+                let (mode, nick, on) = match *mode {
+                    Mode::Plus(ref m, Some(ref nick)) => (m, nick, true),
+                    Mode::Minus(ref m, Some(ref nick)) => (m, nick, false),
+                    _ => continue,
+                };
+                let prefix = match *mode {
+                    ChannelMode::Founder => '~',
+                    ChannelMode::Admin => '&',
+                    ChannelMode::Oper => '@',
+                    ChannelMode::Halfop => '%',
+                    ChannelMode::Voice => '+',
+                    _ => continue,
+                };
+                channels.set_prefix(chan, nick, prefix, on);
+            },
+            AatxeCmd::Response(Response::RPL_NAMREPLY, ref args, Some(ref names)) => {
+                let chan = match args.get(2) {
+                    Some(c) => c.to_lowercase(),
+                    None => return,
+                };
+                let pending = channels.names.entry(chan).or_insert_with(HashMap::new);
+                for name in names.split_whitespace() {
+                    let member = Member::parse(name);
+                    pending.insert(member.nick.to_lowercase(), member);
+                }
+            }
+            AatxeCmd::Response(Response::RPL_ENDOFNAMES, ref args, _) => {
+                let chan = match args.get(1) {
+                    Some(c) => c.to_lowercase(),
+                    None => return,
+                };
+                let members = channels.names.remove(&chan).unwrap_or_default();
+                channels.members.insert(chan.clone(), members);
+                channels.changed.insert(chan);
+            }
+            _ => (),
+        }
+    }
+
+    /// The members of a channel, highest prefix first, e.g. `@alice`.
+    pub fn list(&self, chan: &str) -> Vec<String> {
+        let channels = self.0.borrow();
+        let mut members: Vec<&Member> = channels
+            .members
+            .get(&chan.to_lowercase())
+            .into_iter()
+            .flat_map(|m| m.values())
+            .collect();
+        members.sort_by_key(|m| (m.rank(), m.nick.to_lowercase()));
+        members.iter().map(|m| m.display()).collect()
+    }
+
+    /// The current spelling of a nick if it's in the channel.
+    pub fn nick(&self, chan: &str, nick: &str) -> Option<String> {
+        self.0
+            .borrow()
+            .members
+            .get(&chan.to_lowercase())?
+            .get(&nick.to_lowercase())
+            .map(|m| m.nick.clone())
+    }
+
+    /// Rewrites `@nick` mentions of IRC users in the channel to their nick,
+    /// which is what highlights them on IRC.
+    pub fn translate_mentions(&self, chan: &str, text: &str) -> String {
+        text.split(' ')
+            .map(|word| {
+                if !word.starts_with('@') {
+                    return word.to_owned();
+                }
+                let name = word[1..].trim_right_matches(|c: char| ",.:;!?".contains(c));
+                match self.nick(chan, name) {
+                    Some(nick) => format!("{}{}", nick, &word[1 + name.len()..]),
+                    None => word.to_owned(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Whether the members of a channel changed since this was last called.
+    pub fn take_changed(&self, chan: &str) -> bool {
+        self.0.borrow_mut().changed.remove(&chan.to_lowercase())
+    }
+
+    /// e.g. `3 on IRC: @alice, +bob, carol`
+    pub fn summary(&self, chan: &str) -> String {
+        let members = self.list(chan);
+        format!("{} on IRC: {}", members.len(), members.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(line: &str) -> AatxeMsg {
+        line.parse().unwrap()
+    }
+
+    fn members(lines: &[&str]) -> Members {
+        let members = Members::default();
+        for line in lines {
+            members.handle("relay", &msg(line));
+        }
+        members
+    }
+
+    const NAMES: &'static [&'static str] = &[
+        ":relay!r@h JOIN #chan",
+        ":irc.test 353 relay = #chan :relay @+alice +bob carol %dave ~&erin",
+        ":irc.test 366 relay #chan :End of /NAMES list.",
+    ];
+
+    #[test]
+    fn lists_members_by_highest_prefix() {
+        let members = members(NAMES);
+        assert_eq!(
+            members.list("#CHAN"),
+            vec!["~erin", "@alice", "%dave", "+bob", "carol", "relay"]
+        );
+        assert_eq!(members.summary("#chan"), "6 on IRC: ~erin, @alice, %dave, +bob, carol, relay");
+        assert!(members.take_changed("#chan"));
+        assert!(!members.take_changed("#chan"));
+    }
+
+    #[test]
+    fn keeps_remaining_prefixes_when_one_is_taken() {
+        let members = members(NAMES);
+        members.handle("relay", &msg(":op!o@h MODE #chan -o alice"));
+        members.handle("relay", &msg(":op!o@h MODE #chan -q erin"));
+        assert_eq!(
+            members.list("#chan"),
+            vec!["&erin", "%dave", "+alice", "+bob", "carol", "relay"]
+        );
+        members.handle("relay", &msg(":op!o@h MODE #chan +o carol"));
+        assert_eq!(members.list("#chan")[1], "@carol");
+    }
+
+    #[test]
+    fn follows_joins_parts_kicks_and_quits() {
+        let members = members(NAMES);
+        members.handle("relay", &msg(":frank!f@h JOIN #chan"));
+        members.handle("relay", &msg(":bob!b@h PART #chan :bye"));
+        members.handle("relay", &msg(":op!o@h KICK #chan carol :spam"));
+        members.handle("relay", &msg(":dave!d@h QUIT :gone"));
+        assert_eq!(members.list("#chan"), vec!["~erin", "@alice", "frank", "relay"]);
+
+        // leaving the channel forgets everyone in it
+        members.handle("relay", &msg(":op!o@h KICK #chan relay :out"));
+        assert!(members.list("#chan").is_empty());
+    }
+
+    #[test]
+    fn renames_members() {
+        let members = members(NAMES);
+        members.handle("relay", &msg(":alice!a@h NICK :Alicia"));
+        assert_eq!(members.nick("#chan", "alicia"), Some("Alicia".to_owned()));
+        assert_eq!(members.nick("#chan", "alice"), None);
+        assert_eq!(members.list("#chan")[1], "@Alicia");
+
+        // a change of case only
+        members.handle("relay", &msg(":bob!b@h NICK :BOB"));
+        assert_eq!(members.nick("#chan", "bob"), Some("BOB".to_owned()));
+        assert_eq!(members.list("#chan").len(), 6);
+    }
+
+    #[test]
+    fn rename_onto_a_stale_member_leaves_one_entry() {
+        // carol's quit was missed, e.g. during a netsplit
+        let members = members(NAMES);
+        members.handle("relay", &msg(":bob!b@h NICK :carol"));
+        assert_eq!(
+            members.list("#chan"),
+            vec!["~erin", "@alice", "%dave", "+carol", "relay"]
+        );
+    }
+
+    #[test]
+    fn translates_mentions() {
+        let members = members(NAMES);
+        assert_eq!(
+            members.translate_mentions("#chan", "@ALICE, @bob: see @zed and @carol."),
+            "alice, bob: see @zed and carol."
+        );
+    }
+}
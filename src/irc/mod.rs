@@ -1,7 +1,9 @@
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::default::Default;
 use std::error::Error;
 use std::mem::discriminant;
+use std::rc::Rc;
 use std::time::Duration;
 
 use tokio_core::reactor;
@@ -12,37 +14,106 @@ use futures::sync::oneshot;
 
 use message::{PrivMsg, SlackMsg};
 use errors::{SlagErr, SlagErrKind};
+use outbox::{Outbox, OutboxCfg, SharedOutbox};
+use journal::{Direction, SharedJournal};
+use commands::SlackRoster;
+use direct::Conversations;
+use filters::{self, Filters, Verdict};
+use loops::LoopGuard;
 
 use aatxe_irc;
 use aatxe_irc::client::data::Config as AatxeConfig;
+use aatxe_irc::client::ext::ClientExt;
 use aatxe_irc::client::{Client, IrcClient, PackedIrcClient};
 use aatxe_irc::proto::command::Command as AatxeCmd;
 use aatxe_irc::proto::message::Message as AatxeMsg;
-use aatxe_irc::client::ext::ClientExt;
+use aatxe_irc::proto::response::Response;
 use tokio_timer::Timer;
 
+mod bot;
+mod caps;
+mod channels;
+mod ctcp;
+mod members;
+mod nick;
+mod puppets;
+mod sasl;
+mod services;
+mod topics;
+mod tls;
+use self::bot::Bot;
+use self::caps::{Caps, Echoes, Registration};
+use self::channels::{ChannelGuard, Joining};
+use self::ctcp::Ctcp;
+use self::members::Members;
+pub use self::members::PresenceCfg;
+use self::nick::{NickGuard, NickStyle};
+use self::puppets::{PuppetCfg, Puppets};
+use self::sasl::SaslCfg;
+use self::services::{Identification, ServicesCfg};
+use self::topics::TopicSync;
+pub use self::topics::TopicCfg;
+use self::tls::TlsCfg;
+
 #[derive(Deserialize, Serialize)]
 pub struct IrcChan {
     ignored_nicks: HashSet<String>,
     target_chan: String,
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct IrcCfg {
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ServerCfg {
     host: String,
     port: u16,
+    use_ssl: Option<bool>,
+    tls: Option<TlsCfg>,
+}
+
+impl ServerCfg {
+    fn use_ssl(&self) -> bool {
+        self.use_ssl.unwrap_or(true)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct IrcCfg {
+    host: Option<String>,
+    port: Option<u16>,
+    /// Servers to rotate through on connection failures, tried in order.
+    servers: Option<Vec<ServerCfg>>,
     nick: String,
     alt_nicks: Option<Vec<String>>,
     user: String,
     pass: Option<String>,
     use_ssl: Option<bool>,
+    /// Keys for channels that are `+k`.
+    channel_keys: Option<HashMap<String, String>>,
+    sasl: Option<SaslCfg>,
+    tls: Option<TlsCfg>,
+    services: Option<ServicesCfg>,
+    /// How often to check whether the primary nick can be regained, in seconds.
+    regain_interval: Option<u64>,
+    /// Replies to CTCP queries by command, e.g. `VERSION`. An empty reply
+    /// leaves that query unanswered.
+    ctcp_replies: Option<HashMap<String, String>>,
+    /// Gives active slack users IRC connections of their own.
+    puppets: Option<PuppetCfg>,
+    /// How the nicks of slack users are written in relayed messages.
+    nick_style: Option<NickStyle>,
     #[serde(skip)]
     pub channels: HashMap<String, String>,
+    #[serde(skip)]
+    pub outbox: OutboxCfg,
+    #[serde(skip)]
+    pub topics: Option<TopicCfg>,
+    #[serde(skip)]
+    pub presence: Option<PresenceCfg>,
 }
 
 #[derive(Debug)]
 enum IrcOutMsg {
-    FromSlack(SlackMsg),
+    // messages from slack are waiting in the outbox
+    Queued,
     SenderShutdown,
 }
 
@@ -53,28 +124,120 @@ pub enum IrcFailure {
     Connection(aatxe_irc::error::IrcError),
     BadConf(aatxe_irc::error::IrcError),
     CantIdentify(aatxe_irc::error::IrcError),
+    SaslFailed(String),
+    BadNick(String),
     Shutdown,
 }
 
+impl IrcFailure {
+    // failures that might not happen with another server
+    fn is_connection_failure(&self) -> bool {
+        match *self {
+            IrcFailure::Connection(_) => true,
+            _ => false,
+        }
+    }
+}
+
 enum ConnResult {
     Recoverable(mpsc::Receiver<IrcOutMsg>, IrcFailure),
     Shutdown,
 }
 
+const DEFAULT_REGAIN_INTERVAL: u64 = 60;
+// seconds after connecting until messages are sent to channels not joined yet
+const JOIN_TIMEOUT: u64 = 120;
+
+// State that outlives a single connection to the IRC server.
+struct Relay {
+    // shared with the slack side, which queues messages for IRC here
+    irc_outbox: SharedOutbox,
+    slack_outbox: Rc<RefCell<Outbox>>,
+    journal: SharedJournal,
+    conversations: Conversations,
+    roster: SlackRoster,
+    loop_guard: LoopGuard,
+    filters: Filters,
+    timer: Timer,
+    caps: Caps,
+    // messages sent to IRC, until the server confirms them
+    echoes: Echoes,
+    servers: Vec<ServerCfg>,
+    rotation: Rotation,
+}
+
+impl Relay {
+    // What the lost connection never confirmed may not have arrived, so it's sent
+    // again before anything else once reconnected.
+    fn requeue_unconfirmed(&mut self) {
+        let unconfirmed = self.echoes.take_unconfirmed();
+        if unconfirmed.is_empty() {
+            return;
+        }
+        warn!("sending {} unconfirmed messages to IRC again", unconfirmed.len());
+        self.irc_outbox.lock().unwrap().requeue(unconfirmed);
+    }
+}
+
+const DEFAULT_PORT: u16 = 6697;
+
 impl IrcCfg {
-    fn conn_from_cfg(&self) -> AatxeConfig {
-        AatxeConfig {
+    // The configured server list, with the top level host, port and TLS settings
+    // making up the first server.
+    fn servers(&self) -> Vec<ServerCfg> {
+        let mut servers = vec![];
+        if let Some(ref host) = self.host {
+            servers.push(ServerCfg {
+                host: host.clone(),
+                port: self.port.unwrap_or(DEFAULT_PORT),
+                use_ssl: self.use_ssl,
+                tls: self.tls.clone(),
+            });
+        }
+        servers.extend(self.servers.iter().flat_map(|s| s.iter().cloned()));
+        servers
+    }
+
+    fn conn_from_cfg(&self, server: &ServerCfg) -> AatxeConfig {
+        let mut cfg = AatxeConfig {
             nickname: Some(self.nick.clone()),
-            alt_nicks: self.alt_nicks.clone(),
-            server: Some(self.host.clone()),
-            port: Some(self.port),
-            channels: Some(self.channels.keys().cloned().collect()),
-            use_ssl: Some(self.use_ssl.unwrap_or(true)),
+            alt_nicks: Some(nick::fallback_nicks(&self.nick, self.alt_nicks.as_ref())),
+            server: Some(server.host.clone()),
+            port: Some(server.port),
+            channels: Some(if self.delays_join() {
+                vec![]
+            } else {
+                self.channels.keys().cloned().collect()
+            }),
+            use_ssl: Some(server.use_ssl()),
             ping_time: Some(5),
             ping_timeout: Some(5),
             password: self.pass.clone(),
+            channel_keys: self.channel_keys.clone(),
             ..Default::default()
+        };
+        if let Some(ref tls) = server.tls {
+            tls.apply(&mut cfg);
         }
+        cfg
+    }
+
+    fn channel_list(&self) -> Vec<(String, Option<String>)> {
+        let keys = self.channel_keys.clone().unwrap_or_default();
+        self.channels
+            .keys()
+            .map(|chan| (chan.clone(), keys.get(chan).cloned()))
+            .collect()
+    }
+
+    // When waiting for services, the channels are joined by Identification instead
+    // of the IRC library.
+    fn delays_join(&self) -> bool {
+        let waits = self.services
+            .as_ref()
+            .map(|s| s.waits_for_identify())
+            .unwrap_or(false);
+        self.sasl.is_none() && waits
     }
 
     // TODO: consider futurizing this function
@@ -83,9 +246,25 @@ impl IrcCfg {
         core: &mut reactor::Core,
         shutdown_chan: mpsc::Sender<IrcOutMsg>,
         in_stream: mpsc::Receiver<IrcOutMsg>,
-        mut slack_chan: &mut mpsc::Sender<SlackMsg>,
+        slack_chan: &mut mpsc::Sender<SlackMsg>,
+        relay: &mut Relay,
     ) -> ConnResult {
-        let cfg = self.conn_from_cfg();
+        let Relay {
+            ref irc_outbox,
+            ref slack_outbox,
+            ref journal,
+            ref conversations,
+            ref roster,
+            ref loop_guard,
+            ref filters,
+            ref timer,
+            ref caps,
+            ref echoes,
+            ref servers,
+            ref mut rotation,
+        } = *relay;
+        let server = &servers[rotation.current()];
+        let cfg = self.conn_from_cfg(server);
 
         let future = match IrcClient::new_future(core.handle(), &cfg) {
             Ok(f) => f,
@@ -98,6 +277,7 @@ impl IrcCfg {
             Err(e) => return ConnResult::Recoverable(in_stream, IrcFailure::Connection(e)),
         };
         let PackedIrcClient(client, inner_fut) = client;
+        rotation.connected();
         let inner_fut = inner_fut.map_err(|e| {
             error!("error with connection: {:?}", e);
             ()
@@ -105,18 +285,144 @@ impl IrcCfg {
 
         core.handle().spawn(inner_fut);
 
-        if let Err(e) = client.identify() {
-            return ConnResult::Recoverable(in_stream, IrcFailure::CantIdentify(e));
+        caps.clear();
+        let mut registration = Registration::new(self.sasl.clone(), caps.clone());
+        if let Err(e) = registration.start(&client) {
+            return ConnResult::Recoverable(in_stream, e);
         }
 
+        let to_slack = SlackOut {
+            chan: Rc::new(RefCell::new(slack_chan.clone())),
+            outbox: slack_outbox.clone(),
+            journal: journal.clone(),
+        };
+        to_slack.flush();
+
+        let puppets = Rc::new(Puppets::new(
+            self.puppets.clone(),
+            self.conn_from_cfg(server),
+            self.channels.keys().cloned().collect(),
+            timer.clone(),
+            core.handle(),
+        ));
+
+        let members = Rc::new(Members::default());
+
+        let bot = Rc::new(Bot::new(
+            roster.clone(),
+            members.clone(),
+            filters.clone(),
+            self.channels.keys().cloned().collect(),
+            server.host.clone(),
+            to_slack.clone(),
+        ));
+
+        let topic_sync = Rc::new(TopicSync::new(
+            self.topics.clone(),
+            roster.clone(),
+            to_slack.clone(),
+        ));
+
+        let joining = Rc::new(Joining::new(self.channels.keys()));
+
         let sender = client.clone();
         let (sender_tx, sender_join) = oneshot::channel();
-        let slack_sender = consume_sender(in_stream, sender)
+        let outgoing = Rc::new(Outgoing {
+            client: sender,
+            puppets: puppets.clone(),
+            bot: bot.clone(),
+            topic_sync: topic_sync.clone(),
+            members: members.clone(),
+            nick_style: self.nick_style.clone().unwrap_or_default(),
+            echoes: echoes.clone(),
+            journal: journal.clone(),
+            outbox: irc_outbox.clone(),
+            joining: joining.clone(),
+        });
+        let slack_sender = consume_sender(in_stream, outgoing.clone())
             .then(|res| sender_tx.send(res))
             .map_err(|_| ());
 
         core.handle().spawn(slack_sender);
 
+        let nick_guard = Rc::new(NickGuard::new(self.nick.clone(), self.services.clone()));
+        let (check_client, check_guard) = (client.clone(), nick_guard.clone());
+        let regain_interval = self.regain_interval.unwrap_or(DEFAULT_REGAIN_INTERVAL);
+        // the checks stop once checks_guard is dropped along with the connection
+        let (checks_guard, checks_stopped) = oneshot::channel::<()>();
+        let nick_checks = timer
+            .interval(Duration::from_secs(regain_interval))
+            .map_err(|e| error!("nick check timer failed: {}", e))
+            .for_each(move |_| check_guard.check(&check_client).map_err(|_| ()))
+            .select2(checks_stopped)
+            .then(|_| Ok(()));
+        core.handle().spawn(nick_checks);
+
+        // likewise for the presence view
+        let (presence_guard, presence_stopped) = oneshot::channel::<()>();
+        if let Some(ref presence) = self.presence {
+            let chans: Vec<String> = self.channels.keys().cloned().collect();
+            let (presence_members, presence_out) = (members.clone(), to_slack.clone());
+            let updates = timer
+                .interval(Duration::from_secs(presence.interval()))
+                .map_err(|e| error!("presence timer failed: {}", e))
+                .for_each(move |_| {
+                    for chan in chans.iter().filter(|c| presence_members.take_changed(c)) {
+                        let msg = SlackMsg::PresenceMsg(PrivMsg {
+                            chan: chan.clone(),
+                            msg: presence_members.summary(chan),
+                            ..Default::default()
+                        });
+                        presence_out.send(msg);
+                    }
+                    Ok(())
+                })
+                .select2(presence_stopped)
+                .then(|_| Ok(()));
+            core.handle().spawn(updates);
+        }
+
+        let identification = Identification::new(
+            self.services.clone(),
+            self.nick.clone(),
+            self.channel_list(),
+            self.sasl.is_some(),
+            timer.clone(),
+            core.handle(),
+        );
+
+        let channel_guard = ChannelGuard::new(
+            self.channel_keys.clone().unwrap_or_default(),
+            timer.clone(),
+            core.handle(),
+        );
+
+        // messages for channels that still aren't joined by now are sent anyway, to
+        // find out what's wrong rather than hold them forever
+        let (join_guard, join_stopped) = oneshot::channel::<()>();
+        let (late_joining, late_outgoing) = (joining.clone(), outgoing.clone());
+        let join_timeout = timer
+            .sleep(Duration::from_secs(JOIN_TIMEOUT))
+            .map_err(|e| error!("join timer failed: {}", e))
+            .map(move |_| {
+                let late = late_joining.give_up();
+                if !late.is_empty() {
+                    let late = late.join(", ");
+                    warn!("still not in {} after {}s, sending anyway", late, JOIN_TIMEOUT);
+                    late_outgoing.flush();
+                }
+            })
+            .select2(join_stopped)
+            .then(|_| Ok(()));
+        core.handle().spawn(join_timeout);
+
+        let ctcp = Ctcp::new(self.ctcp_replies.clone());
+
+        let reg_client = client.clone();
+        let (echo_client, echo_journal) = (client.clone(), journal.clone());
+        let (dm_client, bot_client) = (client.clone(), client.clone());
+        let kick_client = client.clone();
+        let ignore_guard = loop_guard.clone();
         let work = client
             .stream()
             // errors here mean a disconnection
@@ -124,20 +430,79 @@ impl IrcCfg {
                 error!("got IRC error {:?}", e);
                 IrcFailure::Disconnect
             })
-            .filter_map(handle_irc_msg)
+            .and_then(move |msg| -> Result<AatxeMsg, IrcFailure> {
+                registration.handle(&reg_client, &msg)?;
+                nick_guard.handle(&reg_client, &msg)?;
+                identification.handle(&reg_client, &msg)?;
+                channel_guard.handle(&reg_client, &msg);
+                // messages from slack wait until the channels they're for are joined
+                if joining.handle(reg_client.current_nickname(), &msg) {
+                    outgoing.flush();
+                }
+                members.handle(reg_client.current_nickname(), &msg);
+                topic_sync
+                    .handle(&reg_client, &msg)
+                    .map_err(IrcFailure::Connection)?;
+                ctcp.handle(&reg_client, &msg)?;
+                Ok(msg)
+            })
+            // our own messages only come back as delivery confirmations
+            .filter(move |msg| !echoes.handle(&echo_client, msg, &echo_journal))
+            .filter(move |msg| !puppets.is_puppet(msg.source_nickname()))
+            // other relays
+            .filter(move |msg| !ignore_guard.ignores_irc(msg.source_nickname()))
+            // commands are answered rather than relayed
+            .filter(move |msg| !bot.handle(&bot_client, msg))
+            .filter_map(move |msg| handle_irc_msg(msg, kick_client.current_nickname()))
             .for_each(|msg| match msg {
-                Incoming::ForwardMsg(m) => {
-                    try_send_to_slack(&mut slack_chan, m);
+                Incoming::ForwardMsg(mut m) => {
+                    let relayed = match m {
+                        SlackMsg::OutMsg(ref mut p)
+                        | SlackMsg::ActionMsg(ref mut p)
+                        | SlackMsg::NoticeMsg(ref mut p) => {
+                            filter_for_slack(filters, &dm_client, p, false)
+                        }
+                        _ => true,
+                    };
+                    if !relayed {
+                        return Ok(());
+                    }
+                    let repeated = {
+                        let p = m.priv_msg();
+                        loop_guard.repeated(Direction::ToSlack, &p.chan, &p.msg)
+                    };
+                    if repeated {
+                        return Ok(());
+                    }
+                    journal.lock().unwrap().record(Direction::ToSlack, &mut m);
+                    to_slack.send(m);
+                    Ok(())
+                }
+                Incoming::DirectMsg(m) => {
+                    let nick = m.nick.clone();
+                    match conversations.from_irc(m) {
+                        Ok(mut m) => if filter_for_slack(filters, &dm_client, &mut m, true) {
+                            let mut m = SlackMsg::DirectMsg(m);
+                            journal.lock().unwrap().record(Direction::ToSlack, &mut m);
+                            to_slack.send(m);
+                        },
+                        Err(reason) => if let Err(e) = dm_client.send_notice(&nick, &reason) {
+                            warn!("failed to tell {} about an undelivered message: {:?}", nick, e);
+                        },
+                    }
                     Ok(())
                 }
                 Incoming::Error(e) => Err(IrcFailure::Error(e)),
             })
-            .then(|res: Result<(), IrcFailure>| {
+            .then(move |res: Result<(), IrcFailure>| {
+                drop(checks_guard);
+                drop(presence_guard);
+                drop(join_guard);
                 shutdown_chan.send(IrcOutMsg::SenderShutdown)
                     .then(|_| res)
             });
 
-        info!("connected to IRC");
+        info!("connected to IRC server {}:{}", server.host, server.port);
         let recv_err = core.run(work).err().unwrap_or(IrcFailure::Disconnect);
         let send_res = core.run(sender_join).unwrap();
         // If the sender errors, then slack has stopped sending messages.
@@ -154,11 +519,17 @@ impl IrcCfg {
     pub fn run(
         &mut self,
         core: &mut reactor::Core,
-        in_stream: mpsc::Receiver<SlackMsg>,
+        in_stream: mpsc::Receiver<()>,
+        irc_outbox: SharedOutbox,
         slack_chan: &mut mpsc::Sender<SlackMsg>,
+        journal: SharedJournal,
+        conversations: Conversations,
+        roster: SlackRoster,
+        loop_guard: LoopGuard,
+        filters: Filters,
     ) -> Result<(), SlagErr> {
         let (sink_in, mut sink_out) = mpsc::channel(32);
-        let slack_msgs = in_stream.map(|m| IrcOutMsg::FromSlack(m)).map_err(|_| ());
+        let slack_msgs = in_stream.map(|_| IrcOutMsg::Queued).map_err(|_| ());
 
         let slack_pipe = sink_in
             .clone()
@@ -167,22 +538,50 @@ impl IrcCfg {
             .then(|_| Ok(()));
         core.handle().spawn(slack_pipe);
 
+        let servers = self.servers();
+        if servers.is_empty() {
+            return Err("no IRC server configured".into());
+        }
+
         let mut err_state = ErrState::new();
-        let timer = Timer::default();
+        let caps = Caps::default();
+        let mut relay = Relay {
+            irc_outbox: irc_outbox,
+            slack_outbox: Rc::new(RefCell::new(self.outbox.slack_outbox(journal.clone()))),
+            journal: journal,
+            conversations: conversations,
+            roster: roster,
+            loop_guard: loop_guard,
+            filters: filters,
+            timer: Timer::default(),
+            caps: caps.clone(),
+            echoes: Echoes::new(caps),
+            rotation: Rotation::new(servers.len()),
+            servers: servers,
+        };
 
         loop {
-            match self.run_once(core, sink_in.clone(), sink_out, slack_chan) {
+            match self.run_once(core, sink_in.clone(), sink_out, slack_chan, &mut relay) {
                 ConnResult::Recoverable(slack_chan, err) => {
                     error!("got irc err- {:?}", err);
                     sink_out = slack_chan;
-                    match err_state.handle_error(err) {
+                    relay.requeue_unconfirmed();
+                    let resolution = if err.is_connection_failure() {
+                        ErrResolution::Backoff(relay.rotation.failed())
+                    } else {
+                        err_state.handle_error(err)
+                    };
+                    match resolution {
                         ErrResolution::Die(e) => {
                             return Err(SlagErrKind::IrcError(e).into());
                         }
                         ErrResolution::Backoff(0) => continue,
                         ErrResolution::Backoff(time) => {
-                            let sleep = timer.sleep(Duration::from_secs(time));
-                            core.run(sleep).expect("failed to sleep");
+                            // messages from slack wait in the outbox meanwhile
+                            let sleep = relay.timer.sleep(Duration::from_secs(time));
+                            if core.run(sleep).is_err() {
+                                panic!("failed to sleep");
+                            }
                             warn!("trying to reconnect to IRC");
                             continue;
                         }
@@ -196,6 +595,45 @@ impl IrcCfg {
     }
 }
 
+const MAX_ROTATION_DELAY: u64 = 300;
+
+// Which server to connect to. Connection failures move on to the next server right
+// away, only once every server has failed is there a pause, which doubles with
+// every pass that fails.
+struct Rotation {
+    servers: usize,
+    current: usize,
+    failed_passes: u32,
+}
+
+impl Rotation {
+    fn new(servers: usize) -> Rotation {
+        Rotation {
+            servers: servers,
+            current: 0,
+            failed_passes: 0,
+        }
+    }
+
+    fn current(&self) -> usize {
+        self.current
+    }
+
+    fn connected(&mut self) {
+        self.failed_passes = 0;
+    }
+
+    // Moves on to the next server, returning the seconds to wait before connecting.
+    fn failed(&mut self) -> u64 {
+        self.current = (self.current + 1) % self.servers;
+        if self.current != 0 {
+            return 0;
+        }
+        self.failed_passes += 1;
+        (1u64 << (self.failed_passes - 1).min(16)).min(MAX_ROTATION_DELAY)
+    }
+}
+
 struct ErrState {
     err: Option<IrcFailure>,
     occurence: u32,
@@ -224,6 +662,8 @@ impl ErrState {
             &IrcFailure::BadConf(_) => ErrResolution::Die(err),
             &IrcFailure::Error(_) => ErrResolution::Die(err),
             &IrcFailure::CantIdentify(_) => ErrResolution::Die(err),
+            &IrcFailure::SaslFailed(_) => ErrResolution::Die(err),
+            &IrcFailure::BadNick(_) => ErrResolution::Die(err),
             &IrcFailure::Shutdown => ErrResolution::Die(err),
         }
     }
@@ -244,113 +684,427 @@ impl ErrState {
     }
 }
 
-// A recursive future that will send messages from output_stream until it runs out or
-// output_stream delivers an IrcOutMsg::SenderShutdown.
+// Joins channels one by one, supplying their keys if any.
+fn join_channels(
+    client: &IrcClient,
+    channels: &[(String, Option<String>)],
+) -> Result<(), aatxe_irc::error::IrcError> {
+    for &(ref chan, ref key) in channels {
+        client.send(AatxeCmd::JOIN(chan.clone(), key.clone(), None))?;
+    }
+    Ok(())
+}
+
+// Where messages from slack go.
+struct Outgoing {
+    client: IrcClient,
+    puppets: Rc<Puppets>,
+    bot: Rc<Bot>,
+    topic_sync: Rc<TopicSync>,
+    members: Rc<Members>,
+    nick_style: NickStyle,
+    echoes: Echoes,
+    journal: SharedJournal,
+    outbox: SharedOutbox,
+    // nothing is sent to a channel before it's joined
+    joining: Rc<Joining>,
+}
+
+impl Outgoing {
+    // Sends what's waiting in the outbox for targets that can be sent to, oldest
+    // first.
+    fn flush(&self) {
+        let joining = &self.joining;
+        let queued = self.outbox
+            .lock()
+            .unwrap()
+            .drain(|msg| joining.is_ready(&msg.priv_msg().chan));
+        for msg in queued {
+            self.send(msg);
+        }
+    }
+
+    fn send(&self, msg: SlackMsg) {
+        let mut msg = match msg {
+            SlackMsg::CommandMsg(m) => {
+                if let Err(e) = self.bot.answer_slack(&self.client, m) {
+                    error!("failed to answer command from slack: {:?}", e);
+                }
+                return;
+            }
+            SlackMsg::TopicMsg(m) => {
+                if let Err(e) = self.topic_sync.from_slack(&self.client, m) {
+                    error!("failed to set topic on IRC: {:?}", e);
+                }
+                return;
+            }
+            other => other,
+        };
+        match msg {
+            SlackMsg::OutMsg(ref mut m) | SlackMsg::ActionMsg(ref mut m) => {
+                m.msg = self.members.translate_mentions(&m.chan, &m.msg);
+            }
+            _ => (),
+        }
+        let id = msg.journal_id();
+        // kept to be sent again if it's never confirmed
+        let original = msg.clone();
+        let sent = self.puppets.send(msg);
+        let cmd = match sent.map(|m| handle_slack_msg(m, &self.nick_style)) {
+            Some(Some(m)) => m,
+            // sent by a puppet or nothing to send
+            _ => {
+                self.journal.lock().unwrap().ack(id);
+                return;
+            }
+        };
+
+        // client.send only queues the message, so it's acknowledged once the server
+        // confirms it
+        let (msg, awaits_echo) = self.echoes.sent(cmd, original, &self.journal);
+        if let Err(e) = self.client.send(msg) {
+            error!("encountered error sending to irc: {:?}", e);
+        }
+        if !awaits_echo {
+            self.journal.lock().unwrap().ack(id);
+        }
+        self.echoes.confirm(&self.client);
+    }
+}
+
+// A recursive future that will send messages from the outbox whenever output_stream
+// says there are some, until it runs out or delivers an IrcOutMsg::SenderShutdown.
 fn consume_sender(
     output_stream: mpsc::Receiver<IrcOutMsg>,
-    sender: IrcClient,
-) -> Box<Future<Item = mpsc::Receiver<IrcOutMsg>, Error = IrcFailure> + Send> {
+    outgoing: Rc<Outgoing>,
+) -> Box<Future<Item = mpsc::Receiver<IrcOutMsg>, Error = IrcFailure>> {
     let next = output_stream.into_future();
-    next.then(|res| {
+    Box::new(next.then(|res| -> Box<Future<Item = _, Error = _>> {
         let (msg, stream) = res.unwrap();
         let msg = match msg {
             Some(m) => m,
             None => {
                 // slack channel down
-                return future::err(IrcFailure::Shutdown).boxed();
+                return Box::new(future::err(IrcFailure::Shutdown));
             }
         };
-        let msg = match msg {
-            IrcOutMsg::SenderShutdown => {
-                return future::ok(stream).boxed();
+        match msg {
+            IrcOutMsg::SenderShutdown => Box::new(future::ok(stream)),
+            IrcOutMsg::Queued => {
+                outgoing.flush();
+                consume_sender(stream, outgoing)
             }
-            IrcOutMsg::FromSlack(m) => m,
-        };
+        }
+    }))
+}
 
-        let msg = match handle_slack_msg(msg) {
-            None => {
-                return consume_sender(stream, sender);
+/// The way to slack from the IRC side. What the channel to slack can't take yet
+/// waits in the outbox, so that it's neither lost nor overtaken.
+#[derive(Clone)]
+pub struct SlackOut {
+    chan: Rc<RefCell<mpsc::Sender<SlackMsg>>>,
+    outbox: Rc<RefCell<Outbox>>,
+    journal: SharedJournal,
+}
+
+impl SlackOut {
+    pub fn send(&self, msg: SlackMsg) {
+        let (mut chan, mut outbox) = (self.chan.borrow_mut(), self.outbox.borrow_mut());
+        try_send_to_slack(&mut chan, &mut outbox, &self.journal, msg);
+    }
+
+    fn flush(&self) {
+        let (mut chan, mut outbox) = (self.chan.borrow_mut(), self.outbox.borrow_mut());
+        flush_to_slack(&mut chan, &mut outbox, &self.journal);
+    }
+}
+
+// Sends as many buffered messages as the channel to slack will take, after
+// summarising stale ones as for IRC. A message is only taken out of the outbox once
+// the channel accepts it, so that it keeps the time it was queued at otherwise.
+fn flush_to_slack(
+    chan: &mut mpsc::Sender<SlackMsg>,
+    outbox: &mut Outbox,
+    journal: &SharedJournal,
+) {
+    outbox.expire();
+    while let Some(m) = outbox.front().cloned() {
+        if let Err(e) = chan.try_send(m) {
+            if e.is_full() {
+                return;
             }
-            Some(m) => m,
-        };
+            let dropped_message = e.into_inner();
+            error!("dropped message '{:?}' when sending to slack", dropped_message);
+            journal.lock().unwrap().ack(dropped_message.journal_id());
+        }
+        outbox.pop();
+    }
+}
 
-        match sender.send(msg) {
-            Ok(_) => consume_sender(stream, sender),
-            Err(e) => {
-                error!("encountered error sending to irc: {:?}", e);
-                consume_sender(stream, sender)
+// Applies the content filters to a message for slack, telling its sender on IRC if
+// it's stopped. Returns whether it should still be relayed.
+fn filter_for_slack(
+    filters: &Filters,
+    client: &IrcClient,
+    msg: &mut PrivMsg,
+    direct: bool,
+) -> bool {
+    let verdict = if direct {
+        filters.apply_direct(Direction::ToSlack, &msg.msg)
+    } else {
+        filters.apply(Direction::ToSlack, &msg.chan, &msg.msg)
+    };
+    match verdict {
+        Verdict::Relay(text) => {
+            msg.msg = text;
+            true
+        }
+        Verdict::Drop(_) => false,
+        Verdict::Warn(rule) => {
+            if let Err(e) = client.send_notice(&msg.nick, &filters::warning(&rule)) {
+                warn!("failed to warn {} about a filtered message: {:?}", msg.nick, e);
             }
+            false
         }
-    }).boxed()
+    }
 }
 
-fn try_send_to_slack(chan: &mut mpsc::Sender<SlackMsg>, slack_msg: SlackMsg) {
+fn try_send_to_slack(
+    chan: &mut mpsc::Sender<SlackMsg>,
+    outbox: &mut Outbox,
+    journal: &SharedJournal,
+    slack_msg: SlackMsg,
+) {
+    flush_to_slack(chan, outbox, journal);
+    // preserve ordering if older messages are still waiting
+    if !outbox.is_empty() {
+        outbox.push(slack_msg);
+        return;
+    }
     if let Err(e) = chan.try_send(slack_msg) {
+        if e.is_full() {
+            outbox.push(e.into_inner());
+            return;
+        }
         let desc = e.description().to_string();
         let dropped_message = e.into_inner();
         error!(
             "dropped message '{:?}' when sending to slack: {}",
             dropped_message, desc
         );
+        journal.lock().unwrap().ack(dropped_message.journal_id());
     }
 }
 
 #[derive(Debug)]
 enum Incoming {
     ForwardMsg(SlackMsg),
+    // a private message to the relay, yet to be addressed to a slack user
+    DirectMsg(PrivMsg),
     //Kick(String),
     Error(String),
 }
 
-fn handle_irc_msg(irc_msg: AatxeMsg) -> Option<Incoming> {
+fn handle_irc_msg(irc_msg: AatxeMsg, own_nick: &str) -> Option<Incoming> {
     let nick = irc_msg.source_nickname()?.to_string();
+    let sender = PrivMsg {
+        nick: nick.clone(),
+        account: caps::tag(&irc_msg, "account"),
+        msgid: caps::tag(&irc_msg, "msgid"),
+        time: caps::tag(&irc_msg, "time"),
+        ..Default::default()
+    };
     let cmd = irc_msg.command;
     match cmd {
-        AatxeCmd::PRIVMSG(target, msg) => Some(handle_privmsg(nick, target, msg)),
-        //AatxeCmd::KICK(chans, users, comment) =>
+        AatxeCmd::PRIVMSG(target, msg) => handle_privmsg(sender, target, msg),
+        // only channel notices are relayed, the rest are from services and servers
+        AatxeCmd::NOTICE(ref target, _) if !is_channel(target) => None,
+        AatxeCmd::NOTICE(_, ref msg) if ctcp::parse(msg).is_some() => None,
+        AatxeCmd::NOTICE(target, msg) => {
+            let notice = SlackMsg::NoticeMsg(PrivMsg {
+                chan: target,
+                msg: msg,
+                ..sender
+            });
+            Some(Incoming::ForwardMsg(notice))
+        }
+        // only the relay's own kicks matter to slack, it's about to rejoin
+        AatxeCmd::KICK(_, ref users, _)
+            if !users.split(',').any(|u| u.eq_ignore_ascii_case(own_nick)) =>
+        {
+            None
+        }
+        AatxeCmd::KICK(chans, _, comment) => {
+            let reason = comment.map(|c| format!(": {}", c)).unwrap_or_default();
+            let text = format!("! {} was kicked by {}{} !", own_nick, nick, reason);
+            Some(status_msg(chans, text))
+        }
+        AatxeCmd::Response(Response::ERR_INVITEONLYCHAN, args, reason)
+        | AatxeCmd::Response(Response::ERR_BANNEDFROMCHAN, args, reason)
+        | AatxeCmd::Response(Response::ERR_BADCHANNELKEY, args, reason)
+        | AatxeCmd::Response(Response::ERR_CHANNELISFULL, args, reason) => {
+            let chan = args.into_iter().nth(1)?;
+            let reason = reason.unwrap_or_else(|| "cannot join channel".to_owned());
+            let text = format!("! failed to join {}: {} !", chan, reason);
+            Some(status_msg(chan, text))
+        }
         AatxeCmd::ERROR(err_message) => Some(Incoming::Error(err_message)),
         _ => None,
     }
 }
 
-fn handle_privmsg(nick: String, target: String, mut msg: String) -> Incoming {
-    let action_preifx = "\x01ACTION";
-    if msg.starts_with(action_preifx) {
-        msg.splice(0..action_preifx.len() + 1, "");
+fn status_msg(chan: String, msg: String) -> Incoming {
+    Incoming::ForwardMsg(SlackMsg::StatusMsg(PrivMsg {
+        chan: chan,
+        msg: msg,
+        ..Default::default()
+    }))
+}
 
-        Incoming::ForwardMsg(SlackMsg::ActionMsg(PrivMsg {
+fn is_channel(target: &str) -> bool {
+    target.starts_with('#') || target.starts_with('&')
+}
+
+// CTCP other than ACTION is either answered by Ctcp or of no interest to slack.
+fn handle_privmsg(sender: PrivMsg, target: String, msg: String) -> Option<Incoming> {
+    let action = match ctcp::parse(&msg) {
+        Some(("ACTION", text)) => Some(text.to_owned()),
+        Some(_) => return None,
+        None => None,
+    };
+    if !is_channel(&target) {
+        let text = match action {
+            Some(text) => format!("* {}", text),
+            None => msg,
+        };
+        return Some(Incoming::DirectMsg(PrivMsg {
             chan: target,
-            msg: msg,
-            nick: nick,
-        }))
-    } else {
-        Incoming::ForwardMsg(SlackMsg::OutMsg(PrivMsg {
+            msg: text,
+            ..sender
+        }));
+    }
+    let forward = match action {
+        Some(text) => SlackMsg::ActionMsg(PrivMsg {
+            chan: target,
+            msg: text,
+            ..sender
+        }),
+        None => SlackMsg::OutMsg(PrivMsg {
             chan: target,
             msg: msg,
-            nick: nick,
-        }))
-    }
+            ..sender
+        }),
+    };
+    Some(Incoming::ForwardMsg(forward))
 }
 
-fn handle_slack_msg(slack_msg: SlackMsg) -> Option<AatxeCmd> {
+fn handle_slack_msg(slack_msg: SlackMsg, style: &NickStyle) -> Option<AatxeCmd> {
     match slack_msg {
-        SlackMsg::OutMsg(m) => try_format_out_msg(m),
-        SlackMsg::ActionMsg(m) => try_format_action_msg(m),
+        SlackMsg::OutMsg(m) => try_format_out_msg(m, style),
+        SlackMsg::ActionMsg(m) => try_format_action_msg(m, style),
+        SlackMsg::NoticeMsg(m) => try_format_notice_msg(m, style),
         SlackMsg::StatusMsg(m) => try_format_status_msg(m),
+        // addressed to a nick rather than a channel
+        SlackMsg::DirectMsg(m) => try_format_out_msg(m, style),
+        // handled by the bot and topic sync, which only happens while connected
+        SlackMsg::CommandMsg(_) | SlackMsg::TopicMsg(_) => None,
+        // only meant for slack
+        SlackMsg::PresenceMsg(_) => None,
     }
 }
 
-fn try_format_out_msg(m: PrivMsg) -> Option<AatxeCmd> {
+fn display_nick(m: &PrivMsg, style: &NickStyle) -> String {
+    style.display(&m.nick, m.user_id.as_ref().map(|u| u.as_str()))
+}
+
+fn try_format_out_msg(m: PrivMsg, style: &NickStyle) -> Option<AatxeCmd> {
     Some(AatxeCmd::PRIVMSG(
         m.chan,
-        format!("[{}]: {}", m.nick, m.msg),
+        format!("[{}]: {}", display_nick(&m, style), m.msg),
     ))
 }
 
-fn try_format_action_msg(m: PrivMsg) -> Option<AatxeCmd> {
-    Some(AatxeCmd::PRIVMSG(m.chan, format!("[{}] {}", m.nick, m.msg)))
+// A CTCP ACTION from the relay would show up as `* relay [nick] waves`, so
+// actions not sent through a puppet are written out as `* nick waves` instead.
+fn try_format_action_msg(m: PrivMsg, style: &NickStyle) -> Option<AatxeCmd> {
+    Some(AatxeCmd::PRIVMSG(
+        m.chan,
+        format!("* {} {}", display_nick(&m, style), m.msg),
+    ))
+}
+
+fn try_format_notice_msg(m: PrivMsg, style: &NickStyle) -> Option<AatxeCmd> {
+    Some(AatxeCmd::NOTICE(m.chan, format!("[{}] {}", display_nick(&m, style), m.msg)))
 }
 
 fn try_format_status_msg(m: PrivMsg) -> Option<AatxeCmd> {
     Some(AatxeCmd::PRIVMSG(m.chan, m.msg))
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::*;
+
+    #[test]
+    fn backs_off_only_after_a_full_pass() {
+        let mut rotation = Rotation::new(3);
+        let schedule: Vec<(u64, usize)> = (0..9)
+            .map(|_| (rotation.failed(), rotation.current()))
+            .collect();
+        assert_eq!(
+            schedule,
+            vec![(0, 1), (0, 2), (1, 0), (0, 1), (0, 2), (2, 0), (0, 1), (0, 2), (4, 0)]
+        );
+
+        // connecting starts the schedule over, from the server that worked
+        rotation.failed();
+        rotation.connected();
+        let delays: Vec<u64> = (0..3).map(|_| rotation.failed()).collect();
+        assert_eq!(delays, vec![0, 1, 0]);
+
+        let mut rotation = Rotation::new(1);
+        let delays: Vec<u64> = (0..12).map(|_| rotation.failed()).collect();
+        assert_eq!(&delays[..5], &[1, 2, 4, 8, 16]);
+        assert_eq!(delays[11], MAX_ROTATION_DELAY);
+        assert_eq!(rotation.current(), 0);
+    }
+
+    #[test]
+    fn writes_out_actions_the_relay_sends() {
+        let m = PrivMsg {
+            nick: "alice".to_owned(),
+            chan: "#chan".to_owned(),
+            msg: "waves".to_owned(),
+            ..Default::default()
+        };
+        match handle_slack_msg(SlackMsg::ActionMsg(m), &NickStyle::default()) {
+            Some(AatxeCmd::PRIVMSG(ref chan, ref text)) => {
+                assert_eq!(chan, "#chan");
+                assert_eq!(text, "* alice waves");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hands_tls_settings_to_the_library() {
+        let cfg = r#"{
+            "nick": "relay",
+            "user": "relay",
+            "servers": [
+                {"host": "a.example", "port": 6697, "tls": {"ca_file": "/etc/ca.der"}},
+                {"host": "b.example", "port": 6667, "use_ssl": false}
+            ]
+        }"#;
+        let cfg: IrcCfg = serde_json::from_str(cfg).unwrap();
+        let servers = cfg.servers();
+        let conn = cfg.conn_from_cfg(&servers[0]);
+        assert_eq!(conn.use_ssl, Some(true));
+        assert_eq!(conn.cert_path, Some("/etc/ca.der".to_owned()));
+        let conn = cfg.conn_from_cfg(&servers[1]);
+        assert_eq!(conn.use_ssl, Some(false));
+        assert_eq!(conn.cert_path, None);
+    }
+}
@@ -0,0 +1,310 @@
+use aatxe_irc::client::{Client, IrcClient};
+use aatxe_irc::error::IrcError;
+use aatxe_irc::proto::command::Command as AatxeCmd;
+use aatxe_irc::proto::message::Message as AatxeMsg;
+use aatxe_irc::proto::response::Response;
+
+use super::IrcFailure;
+use super::services::ServicesCfg;
+
+/// Alternative nicks to try if the primary one is taken: the configured ones
+/// followed by generated ones, so that registration never runs out of nicks.
+pub fn fallback_nicks(nick: &str, configured: Option<&Vec<String>>) -> Vec<String> {
+    let mut nicks = configured.cloned().unwrap_or_default();
+    nicks.push(format!("{}_", nick));
+    nicks.push(format!("{}__", nick));
+    nicks.extend((1..10).map(|i| format!("{}{}", nick, i)));
+    nicks
+}
+
+// characters besides letters and digits that nicks may contain
+const NICK_SPECIALS: &'static str = "-[]\\^_`{|}";
+
+/// Rewrites a name into something that's valid as an IRC nick, replacing
+/// characters that aren't allowed with underscores.
+pub fn sanitize(name: &str) -> String {
+    let mut nick: String = name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || NICK_SPECIALS.contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    // nicks can't start with a digit or a dash
+    match nick.chars().next() {
+        Some(c) if c.is_ascii_digit() || c == '-' => nick.insert(0, '_'),
+        None => nick.push('_'),
+        _ => (),
+    }
+    nick
+}
+
+/// A name as it can be shown in a message on IRC. It isn't a nick, so
+/// anything but line breaks, NULs, spaces and formatting codes is kept.
+pub fn display_name(name: &str) -> String {
+    let name: String = name.chars().filter(|&c| c > ' ').collect();
+    if name.is_empty() {
+        return "_".to_owned();
+    }
+    name
+}
+
+/// How the nicks of slack users are written in relayed messages, so that
+/// IRC users with the same nick aren't highlighted.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mangle {
+    /// A zero-width space after the first character.
+    ZeroWidth,
+    Suffix,
+    /// The nick in a colour.
+    Colour,
+    None,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct NickStyle {
+    /// Defaults to none.
+    mangle: Option<Mangle>,
+    /// Appended with suffix mangling, defaults to `_`.
+    suffix: Option<String>,
+    /// The mIRC colour number used with colour mangling, defaults to 10.
+    colour: Option<u8>,
+    /// Colours every slack user's name in a colour of their own.
+    colour_by_user: Option<bool>,
+    /// mIRC colour numbers to pick from with colour_by_user.
+    palette: Option<Vec<u8>>,
+}
+
+const ZERO_WIDTH_SPACE: char = '\u{200B}';
+const DEFAULT_NICK_SUFFIX: &'static str = "_";
+const DEFAULT_NICK_COLOUR: u8 = 10;
+const COLOUR: char = '\x03';
+// mIRC colours that read well on both dark and light backgrounds
+const DEFAULT_PALETTE: &'static [u8] = &[3, 4, 5, 6, 7, 10, 12, 13, 14];
+// white, black, navy, yellow, light green, light cyan and light grey are too
+// faint on either dark or light backgrounds
+const UNREADABLE: &'static [u8] = &[0, 1, 2, 8, 9, 11, 15];
+
+// FNV-1a, which unlike the standard library's hasher stays the same across
+// releases, so users keep their colour
+fn stable_hash(text: &str) -> u64 {
+    text.bytes()
+        .fold(0xcbf29ce484222325, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+impl NickStyle {
+    /// A slack name as it's shown on IRC. The slack user id picks the colour
+    /// when names are coloured by user.
+    pub fn display(&self, name: &str, user_id: Option<&str>) -> String {
+        let nick = display_name(name);
+        let mangle = self.mangle.unwrap_or(Mangle::None);
+        let nick = match mangle {
+            Mangle::ZeroWidth => {
+                let mut chars = nick.chars();
+                let first = chars.next().map(|c| c.to_string()).unwrap_or_default();
+                format!("{}{}{}", first, ZERO_WIDTH_SPACE, chars.as_str())
+            }
+            Mangle::Suffix => {
+                let suffix = self.suffix.as_ref().map(|s| s.as_str());
+                format!("{}{}", nick, suffix.unwrap_or(DEFAULT_NICK_SUFFIX))
+            }
+            Mangle::Colour | Mangle::None => nick,
+        };
+        let colour = match self.user_colour(user_id.unwrap_or(name)) {
+            Some(c) => Some(c),
+            None if mangle == Mangle::Colour => Some(self.colour.unwrap_or(DEFAULT_NICK_COLOUR)),
+            None => None,
+        };
+        match colour {
+            Some(c) => format!("{}{:02}{}{}", COLOUR, c, nick, COLOUR),
+            None => nick,
+        }
+    }
+
+    fn user_colour(&self, user_id: &str) -> Option<u8> {
+        if !self.colour_by_user.unwrap_or(false) {
+            return None;
+        }
+        let palette: Vec<u8> = self.palette
+            .as_ref()
+            .map(|p| p.as_slice())
+            .unwrap_or(DEFAULT_PALETTE)
+            .iter()
+            .cloned()
+            .filter(|c| *c < 16 && !UNREADABLE.contains(c))
+            .collect();
+        if palette.is_empty() {
+            return None;
+        }
+        Some(palette[(stable_hash(user_id) % palette.len() as u64) as usize])
+    }
+}
+
+/// Keeps trying to get the primary nick back after having to fall back to an
+/// alternative one.
+pub struct NickGuard {
+    primary: String,
+    services: Option<ServicesCfg>,
+}
+
+impl NickGuard {
+    pub fn new(primary: String, services: Option<ServicesCfg>) -> NickGuard {
+        NickGuard {
+            primary: primary,
+            services: services,
+        }
+    }
+
+    fn has_primary(&self, client: &IrcClient) -> bool {
+        client.current_nickname().eq_ignore_ascii_case(&self.primary)
+    }
+
+    fn is_primary(&self, nick: Option<&str>) -> bool {
+        nick.map(|n| n.eq_ignore_ascii_case(&self.primary))
+            .unwrap_or(false)
+    }
+
+    fn take_primary(&self, client: &IrcClient) -> Result<(), IrcError> {
+        info!("trying to regain nick {}", self.primary);
+        client.send(AatxeCmd::NICK(self.primary.clone()))
+    }
+
+    /// Asks the server whether the primary nick is in use. The actual NICK is only
+    /// sent once it's known to be free, as the IRC library gives up on the connection
+    /// when it runs out of alternatives after too many collisions.
+    pub fn check(&self, client: &IrcClient) -> Result<(), IrcError> {
+        if self.has_primary(client) {
+            return Ok(());
+        }
+        client.send(AatxeCmd::ISON(vec![self.primary.clone()]))
+    }
+
+    pub fn handle(&self, client: &IrcClient, msg: &AatxeMsg) -> Result<(), IrcFailure> {
+        let res = match msg.command {
+            AatxeCmd::Response(Response::ERR_ERRONEOUSNICKNAME, ref args, _) => {
+                let nick = args.get(1).cloned().unwrap_or_else(|| self.primary.clone());
+                return Err(IrcFailure::BadNick(nick));
+            }
+            _ if self.has_primary(client) => Ok(()),
+            // registration is done, the holder of the nick can be ghosted now
+            AatxeCmd::Response(Response::RPL_ENDOFMOTD, _, _)
+            | AatxeCmd::Response(Response::ERR_NOMOTD, _, _) => match self.services {
+                Some(ref services) => services.ghost(client, &self.primary),
+                None => self.check(client),
+            },
+            AatxeCmd::Response(Response::RPL_ISON, _, ref online) => {
+                let online = online.as_ref().map(|s| s.as_str()).unwrap_or("");
+                if online.split_whitespace().any(|n| self.is_primary(Some(n))) {
+                    Ok(())
+                } else {
+                    self.take_primary(client)
+                }
+            }
+            AatxeCmd::QUIT(_) | AatxeCmd::NICK(_) if self.is_primary(msg.source_nickname()) => {
+                self.take_primary(client)
+            }
+            _ => Ok(()),
+        };
+        res.map_err(IrcFailure::Connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style(mangle: Mangle) -> NickStyle {
+        NickStyle {
+            mangle: Some(mangle),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sanitizes_nicks() {
+        assert_eq!(sanitize("alice"), "alice");
+        assert_eq!(sanitize("José"), "Jos_");
+        assert_eq!(sanitize("john doe"), "john_doe");
+        assert_eq!(sanitize("1337"), "_1337");
+        assert_eq!(sanitize("-x"), "_-x");
+        assert_eq!(sanitize(""), "_");
+        assert_eq!(sanitize("[a]{b}|c^"), "[a]{b}|c^");
+    }
+
+    #[test]
+    fn display_names_keep_unicode() {
+        assert_eq!(display_name("José"), "José");
+        assert_eq!(display_name("山田"), "山田");
+        assert_ne!(display_name("山田"), display_name("田中"));
+        assert_eq!(display_name("john doe\r\n"), "johndoe");
+        assert_eq!(display_name("\x02bold\x0f\x0304red\x03\0"), "bold04red");
+        assert_eq!(display_name(" \t"), "_");
+    }
+
+    #[test]
+    fn names_are_left_alone_by_default() {
+        let style = NickStyle::default();
+        assert_eq!(style.display("José", Some("U1")), "José");
+    }
+
+    #[test]
+    fn mangles_names() {
+        assert_eq!(style(Mangle::None).display("bob", None), "bob");
+        assert_eq!(style(Mangle::ZeroWidth).display("bob", None), "b\u{200B}ob");
+        assert_eq!(style(Mangle::ZeroWidth).display("山田", None), "山\u{200B}田");
+        assert_eq!(style(Mangle::Suffix).display("bob", None), "bob_");
+        assert_eq!(style(Mangle::Colour).display("bob", None), "\x0310bob\x03");
+
+        let custom = NickStyle {
+            mangle: Some(Mangle::Suffix),
+            suffix: Some("[s]".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(custom.display("bob", None), "bob[s]");
+    }
+
+    #[test]
+    fn colours_by_user() {
+        let style = NickStyle {
+            colour_by_user: Some(true),
+            palette: Some(vec![0, 4, 16]),
+            ..Default::default()
+        };
+        // only 4 is left of the palette
+        assert_eq!(style.display("bob", Some("U1")), "\x0304bob\x03");
+        assert_eq!(style.display("bob", None), "\x0304bob\x03");
+
+        let style = NickStyle {
+            colour_by_user: Some(true),
+            ..Default::default()
+        };
+        let colour = style.user_colour("U024BE7LH").unwrap();
+        assert!(DEFAULT_PALETTE.contains(&colour));
+        assert_eq!(style.user_colour("U024BE7LH"), Some(colour));
+
+        let unreadable = NickStyle {
+            colour_by_user: Some(true),
+            palette: Some(vec![0, 1]),
+            ..Default::default()
+        };
+        assert_eq!(unreadable.display("bob", Some("U1")), "bob");
+    }
+
+    #[test]
+    fn hashes_stably() {
+        // FNV-1a test vectors
+        assert_eq!(stable_hash(""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash("foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn falls_back_to_generated_nicks() {
+        let nicks = fallback_nicks("relay", Some(&vec!["relay2".to_owned()]));
+        assert_eq!(&nicks[..4], &["relay2", "relay_", "relay__", "relay1"]);
+        assert_eq!(nicks.last().map(|n| n.as_str()), Some("relay9"));
+    }
+}
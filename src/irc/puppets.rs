@@ -0,0 +1,285 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+
+use aatxe_irc::client::data::Config as AatxeConfig;
+use aatxe_irc::client::ext::ClientExt;
+use aatxe_irc::client::{Client, IrcClient, PackedIrcClient};
+use aatxe_irc::proto::command::Command as AatxeCmd;
+
+use message::{PrivMsg, SlackMsg};
+
+use super::nick::{fallback_nicks, sanitize};
+use super::IrcFailure;
+
+const DEFAULT_SUFFIX: &'static str = "[s]";
+const DEFAULT_IDLE_TIMEOUT: u64 = 3600;
+const DEFAULT_MAX_CONNECTIONS: usize = 10;
+// short enough for most networks' NICKLEN
+const MAX_NICK_LEN: usize = 16;
+const IDLE_CHECK_INTERVAL: u64 = 60;
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PuppetCfg {
+    /// Appended to the nicks of puppets.
+    suffix: Option<String>,
+    /// Seconds of inactivity after which a puppet is disconnected.
+    idle_timeout: Option<u64>,
+    /// The most puppets connected at once.
+    max_connections: Option<usize>,
+}
+
+impl PuppetCfg {
+    fn nick(&self, name: &str) -> String {
+        let suffix = sanitize(self.suffix.as_ref().map(|s| s.as_str()).unwrap_or(DEFAULT_SUFFIX));
+        let mut nick = sanitize(name);
+        let room = MAX_NICK_LEN.saturating_sub(suffix.len()).max(1);
+        // sanitized nicks are ASCII
+        nick.truncate(room);
+        nick + &suffix
+    }
+}
+
+enum State {
+    Connecting(String),
+    Ready(IrcClient),
+}
+
+struct Puppet {
+    // tells apart successive connections for the same user
+    id: u64,
+    state: State,
+    last_active: Instant,
+}
+
+impl Puppet {
+    fn nick(&self) -> &str {
+        match self.state {
+            State::Connecting(ref nick) => nick,
+            State::Ready(ref client) => client.current_nickname(),
+        }
+    }
+}
+
+type PuppetMap = Rc<RefCell<HashMap<String, Puppet>>>;
+
+/// IRC connections of their own for active slack users, so that they show up
+/// under their own nick rather than as the relay.
+pub struct Puppets {
+    cfg: Option<PuppetCfg>,
+    // the relay's connection settings, which puppets are derived from
+    base: AatxeConfig,
+    channels: Vec<String>,
+    // by slack nick
+    puppets: PuppetMap,
+    next_id: Cell<u64>,
+    handle: Handle,
+}
+
+impl Puppets {
+    pub fn new(
+        cfg: Option<PuppetCfg>,
+        base: AatxeConfig,
+        channels: Vec<String>,
+        timer: Timer,
+        handle: Handle,
+    ) -> Puppets {
+        let puppets = Rc::new(RefCell::new(HashMap::new()));
+        if let Some(ref cfg) = cfg {
+            let idle_timeout = cfg.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT);
+            let idle_timeout = Duration::from_secs(idle_timeout);
+            handle.spawn(disconnect_idle(Rc::downgrade(&puppets), idle_timeout, &timer));
+        }
+        Puppets {
+            cfg: cfg,
+            base: base,
+            channels: channels,
+            puppets: puppets,
+            next_id: Cell::new(0),
+            handle: handle,
+        }
+    }
+
+    /// Whether a nick belongs to one of the puppets.
+    pub fn is_puppet(&self, nick: Option<&str>) -> bool {
+        let nick = match nick {
+            Some(n) => n,
+            None => return false,
+        };
+        self.puppets
+            .borrow()
+            .values()
+            .any(|p| p.nick().eq_ignore_ascii_case(nick))
+    }
+
+    /// Sends a message through the puppet of its sender. The message is handed
+    /// back if it has to be sent by the relay instead, e.g. while the puppet is
+    /// still connecting or too many puppets are connected already.
+    pub fn send(&self, msg: SlackMsg) -> Option<SlackMsg> {
+        let cfg = match self.cfg {
+            Some(ref cfg) => cfg,
+            None => return Some(msg),
+        };
+        let (nick, cmd) = match msg {
+            SlackMsg::OutMsg(PrivMsg { ref nick, ref chan, msg: ref text, .. }) => {
+                (nick.clone(), AatxeCmd::PRIVMSG(chan.clone(), text.clone()))
+            }
+            SlackMsg::ActionMsg(PrivMsg { ref nick, ref chan, msg: ref text, .. }) => {
+                let text = format!("\x01ACTION {}\x01", text);
+                (nick.clone(), AatxeCmd::PRIVMSG(chan.clone(), text))
+            }
+            _ => return Some(msg),
+        };
+        if nick.is_empty() {
+            return Some(msg);
+        }
+
+        let mut puppets = self.puppets.borrow_mut();
+        if let Some(puppet) = puppets.get_mut(&nick) {
+            puppet.last_active = Instant::now();
+            if let State::Ready(ref client) = puppet.state {
+                match client.send(cmd) {
+                    Ok(_) => return None,
+                    Err(e) => warn!("failed to send as the puppet of {}: {:?}", nick, e),
+                }
+            }
+            return Some(msg);
+        }
+        if puppets.len() >= cfg.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS) {
+            debug!("too many puppets connected, relaying for {}", nick);
+            return Some(msg);
+        }
+        drop(puppets);
+        self.connect(cfg, &nick);
+        Some(msg)
+    }
+
+    fn connect(&self, cfg: &PuppetCfg, name: &str) {
+        let puppet_nick = cfg.nick(name);
+        info!("connecting puppet {} for {}", puppet_nick, name);
+        let conn_cfg = AatxeConfig {
+            nickname: Some(puppet_nick.clone()),
+            alt_nicks: Some(fallback_nicks(&puppet_nick, None)),
+            username: Some(sanitize(name)),
+            realname: Some(format!("{} on slack", name)),
+            channels: Some(self.channels.clone()),
+            ..self.base.clone()
+        };
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.puppets.borrow_mut().insert(
+            name.to_owned(),
+            Puppet {
+                id: id,
+                state: State::Connecting(puppet_nick),
+                last_active: Instant::now(),
+            },
+        );
+
+        let conn: Box<Future<Item = PackedIrcClient, Error = IrcFailure>> =
+            match IrcClient::new_future(self.handle.clone(), &conn_cfg) {
+                Ok(f) => Box::new(f.map_err(IrcFailure::Connection)),
+                Err(e) => Box::new(future::err(IrcFailure::BadConf(e))),
+            };
+        let inner_handle = self.handle.clone();
+        let (puppets, failed) = (Rc::downgrade(&self.puppets), Rc::downgrade(&self.puppets));
+        let (name, failed_name) = (name.to_owned(), name.to_owned());
+        let work = conn
+            .and_then(move |PackedIrcClient(client, inner_fut)| {
+                client.identify().map_err(IrcFailure::CantIdentify)?;
+                let inner_fut = inner_fut.map_err(|e| debug!("puppet connection failed: {:?}", e));
+                inner_handle.spawn(inner_fut);
+                Ok(client)
+            })
+            .and_then(move |client| {
+                let own = client.clone();
+                // the puppet is ready once it has joined a channel
+                client
+                    .stream()
+                    .map_err(|e| IrcFailure::Error(format!("{:?}", e)))
+                    .for_each(move |msg| {
+                        let joined = match msg.command {
+                            AatxeCmd::JOIN(..) => {
+                                msg.source_nickname() == Some(own.current_nickname())
+                            }
+                            _ => false,
+                        };
+                        if !joined {
+                            return Ok(());
+                        }
+                        let puppets = puppets.upgrade();
+                        let mut puppets = puppets.as_ref().map(|p| p.borrow_mut());
+                        let puppet = puppets
+                            .as_mut()
+                            .and_then(|p| p.get_mut(&name))
+                            .filter(|p| p.id == id);
+                        match puppet {
+                            Some(puppet) => if let State::Connecting(_) = puppet.state {
+                                info!("puppet {} is ready", own.current_nickname());
+                                puppet.state = State::Ready(own.clone());
+                            },
+                            // disconnected while connecting, or the relay went away
+                            None => own.send_quit("").map_err(IrcFailure::CantIdentify)?,
+                        }
+                        Ok(())
+                    })
+            })
+            .then(move |res| {
+                if let Err(e) = res {
+                    warn!("puppet for {} failed: {:?}", failed_name, e);
+                }
+                if let Some(puppets) = failed.upgrade() {
+                    let mut puppets = puppets.borrow_mut();
+                    if puppets.get(&failed_name).map(|p| p.id == id).unwrap_or(false) {
+                        puppets.remove(&failed_name);
+                    }
+                }
+                Ok(())
+            });
+        self.handle.spawn(work);
+    }
+}
+
+impl Drop for Puppets {
+    fn drop(&mut self) {
+        for (_, puppet) in self.puppets.borrow_mut().drain() {
+            if let State::Ready(client) = puppet.state {
+                let _ = client.send_quit("");
+            }
+        }
+    }
+}
+
+// Periodically disconnects puppets that haven't been used for a while, until
+// the puppets go away.
+fn disconnect_idle(
+    puppets: Weak<RefCell<HashMap<String, Puppet>>>,
+    idle_timeout: Duration,
+    timer: &Timer,
+) -> Box<Future<Item = (), Error = ()>> {
+    let checks = timer
+        .interval(Duration::from_secs(IDLE_CHECK_INTERVAL))
+        .map_err(|e| error!("puppet idle timer failed: {}", e))
+        .for_each(move |_| {
+            let puppets = puppets.upgrade().ok_or(())?;
+            let mut puppets = puppets.borrow_mut();
+            let idle: Vec<String> = puppets
+                .iter()
+                .filter(|&(_, p)| p.last_active.elapsed() >= idle_timeout)
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in idle {
+                if let Some(Puppet { state: State::Ready(client), .. }) = puppets.remove(&name) {
+                    info!("disconnecting idle puppet {}", client.current_nickname());
+                    let _ = client.send_quit("");
+                }
+            }
+            Ok(())
+        });
+    Box::new(checks)
+}
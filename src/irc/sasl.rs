@@ -0,0 +1,35 @@
+use base64;
+
+use aatxe_irc::client::IrcClient;
+use aatxe_irc::client::ext::ClientExt;
+use aatxe_irc::error::IrcError;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "mechanism", rename_all = "lowercase")]
+pub enum SaslCfg {
+    /// Authenticates with an account name and password.
+    Plain { account: String, password: String },
+    /// Authenticates with the client TLS certificate (CertFP).
+    External,
+}
+
+impl SaslCfg {
+    /// Starts authenticating once the server has acknowledged the `sasl` capability.
+    pub fn start(&self, client: &IrcClient) -> Result<(), IrcError> {
+        match *self {
+            SaslCfg::Plain { .. } => client.send_sasl_plain(),
+            SaslCfg::External => client.send_sasl_external(),
+        }
+    }
+
+    /// Answers the server's `AUTHENTICATE +`.
+    pub fn respond(&self, client: &IrcClient) -> Result<(), IrcError> {
+        match *self {
+            SaslCfg::Plain { ref account, ref password } => {
+                let creds = format!("{}\0{}\0{}", account, account, password);
+                client.send_sasl(base64::encode(creds.as_bytes()))
+            }
+            SaslCfg::External => client.send_sasl("+"),
+        }
+    }
+}
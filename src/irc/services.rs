@@ -0,0 +1,285 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::Future;
+use tokio_core::reactor;
+use tokio_timer::Timer;
+
+use aatxe_irc::client::{Client, IrcClient};
+use aatxe_irc::error::IrcError;
+use aatxe_irc::proto::command::Command as AatxeCmd;
+use aatxe_irc::proto::message::Message as AatxeMsg;
+use aatxe_irc::proto::response::Response;
+
+use super::{join_channels, IrcFailure};
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ServicesCfg {
+    /// Nick of the nickname service, `NickServ` if omitted.
+    nickserv: Option<String>,
+    password: String,
+    /// Account to identify as, the configured nick if omitted.
+    account: Option<String>,
+    /// Sent to NickServ to disconnect whoever is using our nick. `{nick}` and
+    /// `{password}` are substituted, defaults to `GHOST {nick} {password}`.
+    ghost_command: Option<String>,
+    /// Sent to NickServ after connecting, with `{nick}` being the account.
+    /// Defaults to `IDENTIFY {nick} {password}`.
+    identify_command: Option<String>,
+    /// Whether to hold off joining channels until services confirm the identification.
+    wait_for_identify: Option<bool>,
+    /// Seconds to wait for the confirmation before joining anyway.
+    identify_timeout: Option<u64>,
+}
+
+const DEFAULT_NICKSERV: &'static str = "NickServ";
+const DEFAULT_GHOST_COMMAND: &'static str = "GHOST {nick} {password}";
+const DEFAULT_IDENTIFY_COMMAND: &'static str = "IDENTIFY {nick} {password}";
+const DEFAULT_IDENTIFY_TIMEOUT: u64 = 30;
+// sent once services have applied a cloak, which they only do for identified users
+const RPL_HOSTHIDDEN: u16 = 396;
+
+impl ServicesCfg {
+    fn nickserv(&self) -> &str {
+        self.nickserv.as_ref().map(|s| s.as_str()).unwrap_or(DEFAULT_NICKSERV)
+    }
+
+    pub fn waits_for_identify(&self) -> bool {
+        self.wait_for_identify.unwrap_or(false)
+    }
+
+    fn fill_template(&self, template: &str, nick: &str) -> String {
+        template
+            .replace("{nick}", nick)
+            .replace("{password}", &self.password)
+    }
+
+    pub fn ghost(&self, client: &IrcClient, nick: &str) -> Result<(), IrcError> {
+        let template = self.ghost_command
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or(DEFAULT_GHOST_COMMAND);
+        let cmd = self.fill_template(template, nick);
+        client.send(AatxeCmd::PRIVMSG(self.nickserv().to_owned(), cmd))
+    }
+
+    // The account is named explicitly, as the relay may be using a fallback nick.
+    fn identify_command(&self, primary: &str) -> String {
+        let template = self.identify_command
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or(DEFAULT_IDENTIFY_COMMAND);
+        let account = self.account.as_ref().map(|s| s.as_str()).unwrap_or(primary);
+        self.fill_template(template, account)
+    }
+
+    fn identify(&self, client: &IrcClient, primary: &str) -> Result<(), IrcError> {
+        let cmd = self.identify_command(primary);
+        client.send(AatxeCmd::PRIVMSG(self.nickserv().to_owned(), cmd))
+    }
+
+    // Whether a message says that identifying worked, failed, or neither. 900 is
+    // sent by most services, 396 by networks that cloak identified users, the
+    // notices are the fallback for services that send neither and are matched as
+    // whole phrases, as "You are not identified" mentions it too.
+    fn identify_reply(&self, msg: &AatxeMsg) -> Option<bool> {
+        let text = match msg.command {
+            AatxeCmd::Response(Response::RPL_LOGGEDIN, _, _) => return Some(true),
+            AatxeCmd::Response(resp, _, _) if resp as u16 == RPL_HOSTHIDDEN => {
+                return Some(true)
+            }
+            // if the IRC library doesn't know the numeric
+            AatxeCmd::Raw(ref code, _, _) if code.parse::<u16>().ok() == Some(RPL_HOSTHIDDEN) => {
+                return Some(true)
+            }
+            AatxeCmd::NOTICE(_, ref text) => text.to_lowercase(),
+            _ => return None,
+        };
+        let from_nickserv = msg.source_nickname()
+            .map(|n| n.eq_ignore_ascii_case(self.nickserv()))
+            .unwrap_or(false);
+        if !from_nickserv {
+            return None;
+        }
+        if FAILED_NOTICES.iter().any(|n| text.contains(n)) {
+            return Some(false);
+        }
+        if IDENTIFIED_NOTICES.iter().any(|n| text.contains(n)) {
+            return Some(true);
+        }
+        None
+    }
+}
+
+// as sent by Atheme and Anope
+const IDENTIFIED_NOTICES: [&'static str; 3] = [
+    "you are now identified",
+    "you are successfully identified",
+    "password accepted",
+];
+const FAILED_NOTICES: [&'static str; 5] = [
+    "not identified",
+    "password incorrect",
+    "invalid password",
+    "is not registered",
+    "isn't registered",
+];
+
+/// Identifies to services once registered and, if configured to, joins the
+/// channels only after services have confirmed it.
+pub struct Identification {
+    services: Option<ServicesCfg>,
+    // the configured nick, identified as unless there's an account
+    nick: String,
+    channels: Vec<(String, Option<String>)>,
+    // identified via SASL during registration already
+    sasl: bool,
+    joined: Rc<Cell<bool>>,
+    timer: Timer,
+    handle: reactor::Handle,
+}
+
+impl Identification {
+    pub fn new(
+        services: Option<ServicesCfg>,
+        nick: String,
+        channels: Vec<(String, Option<String>)>,
+        sasl: bool,
+        timer: Timer,
+        handle: reactor::Handle,
+    ) -> Identification {
+        Identification {
+            services: services,
+            nick: nick,
+            channels: channels,
+            sasl: sasl,
+            joined: Rc::new(Cell::new(false)),
+            timer: timer,
+            handle: handle,
+        }
+    }
+
+    fn join(&self, client: &IrcClient) -> Result<(), IrcError> {
+        if self.joined.replace(true) {
+            return Ok(());
+        }
+        join_channels(client, &self.channels)
+    }
+
+    pub fn handle(&self, client: &IrcClient, msg: &AatxeMsg) -> Result<(), IrcFailure> {
+        let services = match self.services {
+            Some(ref s) if !self.sasl => s,
+            _ => return Ok(()),
+        };
+        let res = match msg.command {
+            AatxeCmd::Response(Response::RPL_ENDOFMOTD, _, _)
+            | AatxeCmd::Response(Response::ERR_NOMOTD, _, _) => {
+                services.identify(client, &self.nick).and_then(|_| {
+                    if services.waits_for_identify() {
+                        self.join_after_timeout(client, services);
+                    }
+                    Ok(())
+                })
+            }
+            _ if services.waits_for_identify() => match services.identify_reply(msg) {
+                Some(true) => {
+                    info!("identified to services, joining channels");
+                    self.join(client)
+                }
+                // the channels are still joined after the timeout, if only to report
+                // why they can't be
+                Some(false) => {
+                    error!("failed to identify to services: {}", msg.to_string().trim_right());
+                    Ok(())
+                }
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        };
+        res.map_err(IrcFailure::Connection)
+    }
+
+    fn join_after_timeout(&self, client: &IrcClient, services: &ServicesCfg) {
+        let timeout = services.identify_timeout.unwrap_or(DEFAULT_IDENTIFY_TIMEOUT);
+        let client = client.clone();
+        let channels = self.channels.clone();
+        let joined = self.joined.clone();
+        let join = self.timer
+            .sleep(Duration::from_secs(timeout))
+            .map_err(|e| error!("identify timer failed: {}", e))
+            .and_then(move |_| {
+                if joined.replace(true) {
+                    return Ok(());
+                }
+                warn!("services didn't confirm identification, joining anyway");
+                join_channels(&client, &channels).map_err(|e| error!("failed to join: {:?}", e))
+            });
+        self.handle.spawn(join);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn services() -> ServicesCfg {
+        ServicesCfg {
+            nickserv: None,
+            password: "hunter2".to_owned(),
+            account: None,
+            ghost_command: None,
+            identify_command: None,
+            wait_for_identify: Some(true),
+            identify_timeout: None,
+        }
+    }
+
+    fn reply(line: &str) -> Option<bool> {
+        services().identify_reply(&line.parse().unwrap())
+    }
+
+    fn notice(text: &str) -> Option<bool> {
+        reply(&format!(":NickServ!s@services. NOTICE relay :{}", text))
+    }
+
+    #[test]
+    fn logged_in_numeric_confirms() {
+        let line = ":services. 900 relay relay!r@host relay :You are now logged in as relay";
+        assert_eq!(reply(line), Some(true));
+    }
+
+    #[test]
+    fn hidden_host_confirms() {
+        let line = ":irc.example 396 relay relay/bot :is now your hidden host";
+        assert_eq!(reply(line), Some(true));
+    }
+
+    #[test]
+    fn identifies_as_the_account_or_configured_nick() {
+        let mut services = services();
+        assert_eq!(services.identify_command("relay"), "IDENTIFY relay hunter2");
+        services.account = Some("relays".to_owned());
+        assert_eq!(services.identify_command("relay"), "IDENTIFY relays hunter2");
+    }
+
+    #[test]
+    fn success_notices_confirm() {
+        assert_eq!(notice("You are now identified for relay."), Some(true));
+        assert_eq!(notice("Password accepted - you are now recognized."), Some(true));
+    }
+
+    #[test]
+    fn not_identified_is_a_failure() {
+        assert_eq!(notice("You are not identified."), Some(false));
+        assert_eq!(notice("relay is not identified."), Some(false));
+        assert_eq!(notice("Password incorrect."), Some(false));
+    }
+
+    #[test]
+    fn other_notices_are_ignored() {
+        assert_eq!(notice("This nickname is registered."), None);
+        let line = ":mallory!m@host NOTICE relay :You are now identified for relay.";
+        assert_eq!(reply(line), None);
+    }
+}
@@ -0,0 +1,24 @@
+use std::path::PathBuf;
+
+use aatxe_irc::client::data::Config as AatxeConfig;
+
+// The IRC library does the TLS handshake itself and only connects to the
+// server directly, so these are the settings it can be given.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct TlsCfg {
+    /// DER encoded certificate of a CA to trust in addition to the system ones.
+    ca_file: Option<PathBuf>,
+    /// PKCS#12 archive with the client certificate and key, e.g. for CertFP.
+    client_cert: Option<PathBuf>,
+    /// Password of the client certificate archive.
+    client_cert_pass: Option<String>,
+}
+
+impl TlsCfg {
+    pub fn apply(&self, cfg: &mut AatxeConfig) {
+        let path = |p: &PathBuf| p.to_string_lossy().into_owned();
+        cfg.cert_path = self.ca_file.as_ref().map(&path);
+        cfg.client_cert_path = self.client_cert.as_ref().map(&path);
+        cfg.client_cert_pass = self.client_cert_pass.clone();
+    }
+}
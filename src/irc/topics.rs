@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use aatxe_irc::client::ext::ClientExt;
+use aatxe_irc::client::{Client, IrcClient};
+use aatxe_irc::error::IrcError;
+use aatxe_irc::proto::command::Command as AatxeCmd;
+use aatxe_irc::proto::message::Message as AatxeMsg;
+use aatxe_irc::proto::response::Response;
+
+use commands::SlackRoster;
+use message::{PrivMsg, SlackMsg};
+
+use super::SlackOut;
+
+/// Which side's topic changes are carried over to the other side.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Authority {
+    Irc,
+    Slack,
+    Both,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct TopicCfg {
+    /// Defaults to both.
+    authority: Option<Authority>,
+    /// Overrides by IRC channel.
+    channels: Option<HashMap<String, Authority>>,
+}
+
+impl TopicCfg {
+    fn authority(&self, chan: &str) -> Authority {
+        self.channels
+            .as_ref()
+            .and_then(|c| c.get(chan))
+            .cloned()
+            .or(self.authority)
+            .unwrap_or(Authority::Both)
+    }
+
+    fn from_irc(&self, chan: &str) -> bool {
+        self.authority(chan) != Authority::Slack
+    }
+
+    fn from_slack(&self, chan: &str) -> bool {
+        self.authority(chan) != Authority::Irc
+    }
+}
+
+/// Keeps the topics of mapped channels in sync. The last topic that slack is
+/// known to have is kept in the roster, so changes that are just the other
+/// side catching up aren't carried back.
+pub struct TopicSync {
+    cfg: Option<TopicCfg>,
+    roster: SlackRoster,
+    // topics from slack that were sent to IRC, by channel, along with who set them
+    pending: RefCell<HashMap<String, (String, String)>>,
+    slack: SlackOut,
+}
+
+impl TopicSync {
+    pub fn new(
+        cfg: Option<TopicCfg>,
+        roster: SlackRoster,
+        slack: SlackOut,
+    ) -> TopicSync {
+        TopicSync {
+            cfg: cfg,
+            roster: roster,
+            pending: RefCell::new(HashMap::new()),
+            slack: slack,
+        }
+    }
+
+    // Carries an IRC topic over to slack unless slack already has it.
+    fn sync_to_slack(&self, chan: &str, nick: &str, topic: &str) {
+        if self.roster.topic(chan).as_ref().map(|t| t.as_str()) == Some(topic) {
+            return;
+        }
+        self.roster.set_topic(chan, topic);
+        self.slack.send(SlackMsg::TopicMsg(PrivMsg {
+            nick: nick.to_owned(),
+            chan: chan.to_owned(),
+            msg: topic.to_owned(),
+            ..Default::default()
+        }));
+    }
+
+    pub fn handle(&self, client: &IrcClient, msg: &AatxeMsg) -> Result<(), IrcError> {
+        let cfg = match self.cfg {
+            Some(ref cfg) => cfg,
+            None => return Ok(()),
+        };
+        match msg.command {
+            AatxeCmd::TOPIC(ref chan, Some(ref topic)) => {
+                let nick = msg.source_nickname().unwrap_or("");
+                // our own changes are already in sync
+                if nick == client.current_nickname() {
+                    self.pending.borrow_mut().remove(chan);
+                    return Ok(());
+                }
+                if cfg.from_irc(chan) {
+                    self.sync_to_slack(chan, nick, topic);
+                }
+            }
+            // the topic when joining, which is where slack's authority shows
+            AatxeCmd::Response(Response::RPL_TOPIC, ref args, Some(ref topic)) => {
+                let chan = match args.get(1) {
+                    Some(c) => c,
+                    None => return Ok(()),
+                };
+                match (cfg.authority(chan), self.roster.topic(chan)) {
+                    (Authority::Irc, _) => self.sync_to_slack(chan, "", topic),
+                    (Authority::Slack, Some(ref t)) if t != topic => {
+                        return self.set_topic(client, chan, "", t);
+                    }
+                    _ => (),
+                }
+            }
+            // likewise for channels without a topic, which slack's topic is pushed to
+            // but which don't clear slack's
+            AatxeCmd::Response(Response::RPL_NOTOPIC, ref args, _) => {
+                let chan = match args.get(1) {
+                    Some(c) => c,
+                    None => return Ok(()),
+                };
+                match (cfg.authority(chan), self.roster.topic(chan)) {
+                    (Authority::Slack, Some(ref t)) if !t.is_empty() => {
+                        return self.set_topic(client, chan, "", t);
+                    }
+                    _ => (),
+                }
+            }
+            // the relay isn't allowed to change the topic
+            AatxeCmd::Response(Response::ERR_CHANOPRIVSNEEDED, ref args, _) => {
+                let chan = match args.get(1) {
+                    Some(c) => c,
+                    None => return Ok(()),
+                };
+                if let Some((nick, topic)) = self.pending.borrow_mut().remove(chan) {
+                    let text = if nick.is_empty() {
+                        format!("! the slack topic is: {} !", topic)
+                    } else {
+                        format!("! {} changed the slack topic to: {} !", nick, topic)
+                    };
+                    return client.send_privmsg(chan, &text);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn set_topic(
+        &self,
+        client: &IrcClient,
+        chan: &str,
+        nick: &str,
+        topic: &str,
+    ) -> Result<(), IrcError> {
+        self.pending
+            .borrow_mut()
+            .insert(chan.to_owned(), (nick.to_owned(), topic.to_owned()));
+        client.send(AatxeCmd::TOPIC(chan.to_owned(), Some(topic.to_owned())))
+    }
+
+    /// Carries a topic change on slack over to IRC.
+    pub fn from_slack(&self, client: &IrcClient, msg: PrivMsg) -> Result<(), IrcError> {
+        match self.cfg {
+            Some(ref cfg) if cfg.from_slack(&msg.chan) => {
+                self.set_topic(client, &msg.chan, &msg.nick, &msg.msg)
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use errors::SlagErr;
+use message::SlackMsg;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JournalCfg {
+    path: PathBuf,
+    /// Size in bytes after which the journal gets compacted.
+    max_size: Option<u64>,
+}
+
+const DEFAULT_MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    ToIrc,
+    ToSlack,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+enum Entry {
+    Append {
+        id: u64,
+        dir: Direction,
+        msg: SlackMsg,
+        // seconds since the epoch
+        #[serde(default)]
+        at: u64,
+    },
+    Ack { id: u64 },
+}
+
+pub type SharedJournal = Arc<Mutex<Journal>>;
+
+/// Seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// An append-only log of messages that have been accepted by one side of the
+/// relay but not yet delivered to the other. Every relayed message is appended
+/// before it is queued and acknowledged once it has been delivered, filtered or
+/// dropped for good, so anything left unacknowledged after a crash gets
+/// replayed on the next start. It's the only thing persisted, the outboxes are
+/// refilled from it.
+pub struct Journal {
+    path: Option<PathBuf>,
+    max_size: u64,
+    file: Option<File>,
+    size: u64,
+    next_id: u64,
+    pending: BTreeMap<u64, (Direction, String)>,
+}
+
+impl Journal {
+    /// A journal that doesn't record anything.
+    pub fn disabled() -> Journal {
+        Journal {
+            path: None,
+            max_size: DEFAULT_MAX_SIZE,
+            file: None,
+            size: 0,
+            next_id: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub fn open(cfg: &JournalCfg) -> Result<Journal, SlagErr> {
+        let mut journal = Journal {
+            path: Some(cfg.path.clone()),
+            max_size: cfg.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            file: None,
+            size: 0,
+            next_id: 0,
+            pending: BTreeMap::new(),
+        };
+        journal.load()?;
+        journal.compact()?;
+        Ok(journal)
+    }
+
+    pub fn shared(self) -> SharedJournal {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Unacknowledged messages for a direction with the time they were
+    /// recorded, oldest first.
+    pub fn pending(&self, dir: Direction) -> Vec<(u64, SlackMsg)> {
+        self.pending
+            .iter()
+            .filter(|&(_, &(d, _))| d == dir)
+            .filter_map(|(_, &(_, ref line))| match serde_json::from_str(line) {
+                Ok(Entry::Append { msg, at, .. }) => Some((at, msg)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Records a message and tags it with its journal id. Status messages,
+    /// commands, topics and presence are not worth replaying and are left
+    /// untracked.
+    pub fn record(&mut self, dir: Direction, msg: &mut SlackMsg) {
+        if self.path.is_none() {
+            return;
+        }
+        match *msg {
+            SlackMsg::StatusMsg(_)
+            | SlackMsg::CommandMsg(_)
+            | SlackMsg::TopicMsg(_)
+            | SlackMsg::PresenceMsg(_) => return,
+            SlackMsg::OutMsg(ref mut m)
+            | SlackMsg::ActionMsg(ref mut m)
+            | SlackMsg::NoticeMsg(ref mut m)
+            | SlackMsg::DirectMsg(ref mut m) => {
+                m.journal_id = Some(self.next_id);
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let entry = Entry::Append {
+            id: id,
+            dir: dir,
+            msg: msg.clone(),
+            at: now(),
+        };
+        let line = serde_json::to_string(&entry).expect("failed to serialize journal entry");
+        self.append(&line);
+        self.pending.insert(id, (dir, line));
+    }
+
+    /// Marks a message as done with, be it delivered or dropped. Takes the
+    /// message's journal id, untracked messages are ignored.
+    pub fn ack(&mut self, id: Option<u64>) {
+        let id = match id {
+            Some(id) => id,
+            None => return,
+        };
+        if self.pending.remove(&id).is_none() {
+            return;
+        }
+        let line = serde_json::to_string(&Entry::Ack { id: id })
+            .expect("failed to serialize journal entry");
+        self.append(&line);
+        if self.size > self.max_size {
+            if let Err(e) = self.compact() {
+                error!("failed to compact journal: {}", e);
+            }
+        }
+    }
+
+    fn append(&mut self, line: &str) {
+        let res = match self.file {
+            Some(ref mut f) => writeln!(f, "{}", line).and_then(|_| f.sync_data()),
+            None => return,
+        };
+        match res {
+            Ok(()) => self.size += line.len() as u64 + 1,
+            Err(e) => error!("failed to write to journal: {}", e),
+        }
+    }
+
+    fn load(&mut self) -> Result<(), SlagErr> {
+        let path = self.path.clone().expect("loading a disabled journal");
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(_) => return Ok(()),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(Entry::Append { id, dir, .. }) => {
+                    self.next_id = self.next_id.max(id + 1);
+                    self.pending.insert(id, (dir, line));
+                }
+                Ok(Entry::Ack { id }) => {
+                    self.pending.remove(&id);
+                }
+                // a crash mid-write leaves a truncated last line
+                Err(e) => warn!("skipping corrupt journal entry in {}: {}", path.display(), e),
+            }
+        }
+        info!("{} unacknowledged messages in journal {}", self.pending.len(), path.display());
+        Ok(())
+    }
+
+    // Rewrites the journal with only the pending entries. If that is still over
+    // the size cap, the oldest pending messages are given up on.
+    fn compact(&mut self) -> Result<(), SlagErr> {
+        let path = match self.path {
+            Some(ref p) => p.clone(),
+            None => return Ok(()),
+        };
+        let mut size: u64 = self.pending.values().map(|&(_, ref l)| l.len() as u64 + 1).sum();
+        while size > self.max_size {
+            let oldest = *self.pending.keys().next().expect("journal size without entries");
+            let (_, line) = self.pending.remove(&oldest).unwrap();
+            size -= line.len() as u64 + 1;
+            error!("journal over size cap, dropping message {}", line);
+        }
+
+        let tmp_path = path.with_extension("compact");
+        {
+            let mut w = BufWriter::new(File::create(&tmp_path)?);
+            for &(_, ref line) in self.pending.values() {
+                writeln!(w, "{}", line)?;
+            }
+            w.flush()?;
+            w.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+
+        self.file = Some(OpenOptions::new().append(true).open(&path)?);
+        self.size = size;
+        debug!("compacted journal {} to {} bytes", path.display(), size);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::env;
+
+    use message::PrivMsg;
+
+    use super::*;
+
+    /// A directory of its own for a test, removed afterwards.
+    pub struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> TempDir {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            let dir = env::temp_dir().join(format!("slagw-{}-{}", name, nanos));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        pub fn journal(&self, max_size: Option<u64>) -> Journal {
+            let cfg = JournalCfg {
+                path: self.0.join("journal"),
+                max_size: max_size,
+            };
+            Journal::open(&cfg).unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub fn msg(text: &str) -> SlackMsg {
+        SlackMsg::OutMsg(PrivMsg {
+            nick: "alice".to_owned(),
+            chan: "#chan".to_owned(),
+            msg: text.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    fn texts(pending: Vec<(u64, SlackMsg)>) -> Vec<String> {
+        pending.into_iter().map(|(_, m)| m.priv_msg().msg.clone()).collect()
+    }
+
+    fn record(journal: &mut Journal, dir: Direction, text: &str) -> Option<u64> {
+        let mut m = msg(text);
+        journal.record(dir, &mut m);
+        m.journal_id()
+    }
+
+    #[test]
+    fn replays_unacknowledged_messages() {
+        let dir = TempDir::new("replay");
+        {
+            let mut journal = dir.journal(None);
+            let first = record(&mut journal, Direction::ToIrc, "one");
+            record(&mut journal, Direction::ToSlack, "two");
+            record(&mut journal, Direction::ToIrc, "three");
+            journal.ack(first);
+        }
+        let mut journal = dir.journal(None);
+        assert_eq!(texts(journal.pending(Direction::ToIrc)), vec!["three"]);
+        assert_eq!(texts(journal.pending(Direction::ToSlack)), vec!["two"]);
+        assert!(journal.pending(Direction::ToIrc)[0].0 > 0);
+        // ids aren't reused after a restart
+        assert_eq!(record(&mut journal, Direction::ToIrc, "four"), Some(3));
+    }
+
+    #[test]
+    fn ignores_untracked_messages_and_unknown_acks() {
+        let dir = TempDir::new("untracked");
+        let mut journal = dir.journal(None);
+        let mut status = SlackMsg::StatusMsg(PrivMsg::default());
+        journal.record(Direction::ToSlack, &mut status);
+        assert_eq!(status.journal_id(), None);
+        assert!(journal.pending(Direction::ToSlack).is_empty());
+
+        let id = record(&mut journal, Direction::ToSlack, "one");
+        let size = journal.size;
+        journal.ack(None);
+        journal.ack(Some(42));
+        assert_eq!(journal.size, size);
+        journal.ack(id);
+        assert!(journal.size > size);
+        let acked = journal.size;
+        journal.ack(id);
+        assert_eq!(journal.size, acked);
+
+        assert!(Journal::disabled().pending(Direction::ToIrc).is_empty());
+    }
+
+    #[test]
+    fn skips_a_truncated_last_entry() {
+        let dir = TempDir::new("truncated");
+        {
+            let mut journal = dir.journal(None);
+            record(&mut journal, Direction::ToIrc, "one");
+        }
+        let path = dir.0.join("journal");
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"Append\":{{\"id\":1,\"dir\":\"ToI").unwrap();
+        drop(file);
+        let journal = dir.journal(None);
+        assert_eq!(texts(journal.pending(Direction::ToIrc)), vec!["one"]);
+    }
+
+    #[test]
+    fn compacts_through_a_temporary_file() {
+        let dir = TempDir::new("compact");
+        let path = dir.0.join("journal");
+        let mut journal = dir.journal(None);
+        let ids: Vec<_> = (0..20)
+            .map(|i| record(&mut journal, Direction::ToIrc, &format!("message {:02}", i)))
+            .collect();
+        // a few acks take it over the cap, while all pending messages fit
+        journal.max_size = journal.size + 64;
+        for id in &ids[..19] {
+            journal.ack(*id);
+        }
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("message 00"));
+        assert!(contents.contains("message 19"));
+        assert!(!path.with_extension("compact").exists());
+        assert_eq!(journal.size, contents.len() as u64);
+        assert_eq!(texts(journal.pending(Direction::ToIrc)), vec!["message 19"]);
+
+        // opening compacts as well
+        drop(journal);
+        let journal = dir.journal(None);
+        assert_eq!(texts(journal.pending(Direction::ToIrc)), vec!["message 19"]);
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+    }
+
+    #[test]
+    fn drops_the_oldest_messages_over_the_cap() {
+        let dir = TempDir::new("cap");
+        {
+            let mut journal = dir.journal(None);
+            for i in 0..5 {
+                record(&mut journal, Direction::ToIrc, &format!("message {}", i));
+            }
+        }
+        // the lines only differ in their ids and texts, which are the same length
+        let size = fs::metadata(dir.0.join("journal")).unwrap().len();
+        let journal = dir.journal(Some(size / 5 * 2));
+        assert_eq!(
+            texts(journal.pending(Direction::ToIrc)),
+            vec!["message 3", "message 4"]
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use journal::Direction;
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct LoopCfg {
+    /// Slack user names, user ids and bot ids whose messages aren't relayed.
+    slack_ignore: Option<Vec<String>>,
+    /// IRC nicks whose messages aren't relayed.
+    irc_ignore: Option<Vec<String>>,
+    /// How often the same text may be relayed to a channel within `window`.
+    max_repeats: Option<usize>,
+    /// In seconds.
+    window: Option<u64>,
+}
+
+const DEFAULT_MAX_REPEATS: usize = 3;
+const DEFAULT_WINDOW: u64 = 60;
+// posts to slack remembered for recognising the webhook's own messages
+const MAX_POSTED: usize = 64;
+
+#[derive(Default)]
+struct State {
+    own_user: Option<String>,
+    // bot ids the relay's own webhook posts show up with
+    own_bots: HashSet<String>,
+    // recent posts to slack as (username, text)
+    posted: VecDeque<(String, String)>,
+    // when the same text was relayed to a channel, by direction
+    relayed: HashMap<(Direction, String, String), VecDeque<Instant>>,
+}
+
+/// Keeps the relay from relaying its own messages, those of other relays that
+/// are ignored, and text that keeps coming back.
+#[derive(Clone)]
+pub struct LoopGuard {
+    slack_ignore: Arc<HashSet<String>>,
+    irc_ignore: Arc<HashSet<String>>,
+    max_repeats: usize,
+    window: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+impl LoopGuard {
+    pub fn new(cfg: LoopCfg) -> LoopGuard {
+        let irc_ignore = cfg.irc_ignore
+            .unwrap_or_default()
+            .iter()
+            .map(|n| n.to_lowercase())
+            .collect();
+        LoopGuard {
+            slack_ignore: Arc::new(cfg.slack_ignore.unwrap_or_default().into_iter().collect()),
+            irc_ignore: Arc::new(irc_ignore),
+            max_repeats: cfg.max_repeats.unwrap_or(DEFAULT_MAX_REPEATS),
+            window: Duration::from_secs(cfg.window.unwrap_or(DEFAULT_WINDOW)),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// The slack user the relay is logged in as.
+    pub fn set_own_user(&self, id: &str) {
+        self.state.lock().unwrap().own_user = Some(id.to_owned());
+    }
+
+    /// Remembers a post to slack, so that the webhook's bot id can be told
+    /// when the post comes back.
+    pub fn posted(&self, username: &str, text: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.posted.len() >= MAX_POSTED {
+            state.posted.pop_front();
+        }
+        state.posted.push_back((username.to_owned(), text.to_owned()));
+    }
+
+    /// Whether a slack message was posted by the relay itself, either as its
+    /// user or through its webhook.
+    pub fn is_own_slack(
+        &self,
+        user: Option<&str>,
+        bot_id: Option<&str>,
+        username: Option<&str>,
+        text: &str,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if user.is_some() && user == state.own_user.as_ref().map(|u| u.as_str()) {
+            return true;
+        }
+        let bot_id = match bot_id {
+            Some(b) => b,
+            None => return false,
+        };
+        if state.own_bots.contains(bot_id) {
+            return true;
+        }
+        let username = username.unwrap_or("");
+        let idx = state
+            .posted
+            .iter()
+            .position(|&(ref u, ref t)| u == username && text.contains(t.as_str()));
+        match idx {
+            Some(idx) => {
+                info!("recognised {} as the relay's own webhook", bot_id);
+                state.posted.remove(idx);
+                state.own_bots.insert(bot_id.to_owned());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether any of a slack user's names or ids are on the ignore list.
+    pub fn ignores_slack(&self, names: &[Option<&str>]) -> bool {
+        names
+            .iter()
+            .filter_map(|n| *n)
+            .any(|n| self.slack_ignore.contains(n))
+    }
+
+    pub fn ignores_irc(&self, nick: Option<&str>) -> bool {
+        nick.map(|n| self.irc_ignore.contains(&n.to_lowercase()))
+            .unwrap_or(false)
+    }
+
+    /// Records that a text is being relayed to a channel and tells whether it
+    /// was relayed there too often lately, in which case it shouldn't be.
+    pub fn repeated(&self, dir: Direction, chan: &str, text: &str) -> bool {
+        let window = self.window;
+        let mut state = self.state.lock().unwrap();
+        // forget what wasn't relayed within the window
+        state.relayed.retain(|_, times| {
+            while times.front().map(|t| t.elapsed() >= window).unwrap_or(false) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = state
+            .relayed
+            .entry((dir, chan.to_owned(), text.to_owned()))
+            .or_insert_with(VecDeque::new);
+        if times.len() >= self.max_repeats {
+            warn!("not relaying text that was relayed to {} too often: {}", chan, text);
+            return true;
+        }
+        times.push_back(Instant::now());
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn guard(max_repeats: usize, window: u64) -> LoopGuard {
+        LoopGuard::new(LoopCfg {
+            max_repeats: Some(max_repeats),
+            window: Some(window),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn stops_text_relayed_too_often() {
+        let guard = guard(2, 60);
+        assert!(!guard.repeated(Direction::ToIrc, "#chan", "ping"));
+        assert!(!guard.repeated(Direction::ToIrc, "#chan", "ping"));
+        assert!(guard.repeated(Direction::ToIrc, "#chan", "ping"));
+        assert!(guard.repeated(Direction::ToIrc, "#chan", "ping"));
+        // counted separately by channel, direction and text
+        assert!(!guard.repeated(Direction::ToIrc, "#other", "ping"));
+        assert!(!guard.repeated(Direction::ToSlack, "#chan", "ping"));
+        assert!(!guard.repeated(Direction::ToIrc, "#chan", "pong"));
+    }
+
+    #[test]
+    fn forgets_what_is_outside_the_window() {
+        let guard = guard(1, 1);
+        assert!(!guard.repeated(Direction::ToIrc, "#chan", "ping"));
+        assert!(guard.repeated(Direction::ToIrc, "#chan", "ping"));
+        thread::sleep(Duration::from_millis(1100));
+        assert!(!guard.repeated(Direction::ToIrc, "#chan", "ping"));
+    }
+
+    #[test]
+    fn recognises_own_messages() {
+        let guard = guard(3, 60);
+        guard.set_own_user("U1");
+        assert!(guard.is_own_slack(Some("U1"), None, None, "hi"));
+        assert!(!guard.is_own_slack(Some("U2"), None, None, "hi"));
+
+        // the webhook's bot id is learnt from a post coming back
+        guard.posted("alice", "hello there");
+        assert!(!guard.is_own_slack(None, Some("B1"), Some("bob"), "hello there"));
+        assert!(guard.is_own_slack(None, Some("B1"), Some("alice"), "> hello there"));
+        assert!(guard.is_own_slack(None, Some("B1"), Some("carol"), "anything"));
+        assert!(!guard.is_own_slack(None, Some("B2"), Some("alice"), "hello there"));
+    }
+
+    #[test]
+    fn ignores_configured_senders() {
+        let guard = LoopGuard::new(LoopCfg {
+            slack_ignore: Some(vec!["B9".to_owned()]),
+            irc_ignore: Some(vec!["OtherRelay".to_owned()]),
+            ..Default::default()
+        });
+        assert!(guard.ignores_slack(&[Some("U1"), Some("B9")]));
+        assert!(!guard.ignores_slack(&[Some("U1"), None]));
+        assert!(guard.ignores_irc(Some("otherrelay")));
+        assert!(!guard.ignores_irc(Some("alice")));
+        assert!(!guard.ignores_irc(None));
+    }
+}
@@ -2,10 +2,16 @@
 #![feature(splice)]
 #![allow(unused_doc_comment)]
 
+extern crate base64;
+extern crate chrono;
+extern crate regex;
 extern crate config;
 extern crate futures;
+extern crate hyper;
+extern crate hyper_tls;
 extern crate irc as aatxe_irc;
 extern crate serde;
+extern crate serde_json;
 extern crate simplelog;
 extern crate slack;
 extern crate slack_api;
@@ -13,6 +19,7 @@ extern crate slack_hook;
 extern crate tokio_core;
 extern crate tokio_pool;
 extern crate tokio_timer;
+extern crate url;
 
 
 #[macro_use]
@@ -25,6 +32,7 @@ extern crate log;
 extern crate serde_derive;
 
 
+use std::collections::HashMap;
 use std::thread;
 use tokio_core::reactor::{Core, Handle};
 use futures::sync::mpsc;
@@ -35,8 +43,21 @@ mod irc;
 mod message;
 mod errors;
 mod cfg;
+mod outbox;
+mod journal;
+mod webhook;
+mod direct;
+mod commands;
+mod loops;
+mod filters;
 use slack_client::{SlackReceiver, SlackSender};
 use errors::SlagErr;
+use journal::{Journal, SharedJournal};
+use outbox::SharedOutbox;
+use direct::Conversations;
+use commands::SlackRoster;
+use loops::LoopGuard;
+use filters::Filters;
 
 fn logging_conf() -> simplelog::Config {
     use simplelog::*;
@@ -79,7 +100,8 @@ fn main() {
     // Same as before...
     let mut ev = Core::new().unwrap();
 
-    let (irc_send, irc_receive) = mpsc::channel(1024);
+    // only wakes IRC up, the messages themselves wait in its outbox
+    let (irc_send, irc_receive) = mpsc::channel(1);
     let (mut slack_send, slack_receive) = mpsc::channel(1024);
     let cfg = match get_config(config_file) {
         Ok(c) => c,
@@ -89,9 +111,42 @@ fn main() {
         }
     };
 
+    let journal = match cfg.journal.as_ref().map(Journal::open) {
+        Some(Ok(j)) => j.shared(),
+        Some(Err(e)) => {
+            error!("Failed to open journal: {}", e);
+            return;
+        }
+        None => Journal::disabled().shared(),
+    };
+
+    let irc_outbox = cfg.outbox
+        .clone()
+        .unwrap_or_default()
+        .irc_outbox(journal.clone())
+        .shared();
+    let conversations = Conversations::new(cfg.direct_messages.clone().unwrap_or_default());
+    let roster = SlackRoster::default();
+    let loop_guard = LoopGuard::new(cfg.loops.clone().unwrap_or_default());
+    let filters = match Filters::new(cfg.filters.clone().unwrap_or_default()) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Failed to load filters: {}", e);
+            return;
+        }
+    };
     let (mut irc_cfg, slack_cfg) = cfg.get_cfg();
 
-    let (mut cli, mut slack_agent) = match load_slack_receiver(slack_cfg.clone(), irc_send) {
+    let (mut cli, mut slack_agent) = match load_slack_receiver(
+        slack_cfg.clone(),
+        irc_send,
+        irc_outbox.clone(),
+        journal.clone(),
+        conversations.clone(),
+        roster.clone(),
+        loop_guard.clone(),
+        filters.clone(),
+    ) {
         Ok(slack) => slack,
         Err(e) => {
             error!("Failed to load slack: {}", e.description());
@@ -100,7 +155,14 @@ fn main() {
     };
 
     let slack_client_secret = slack_cfg.secret.to_string();
-    let slack_sender = match load_slack_sink(slack_receive, slack_cfg, &ev.handle()) {
+    let slack_sender = match load_slack_sink(
+        slack_receive,
+        slack_cfg,
+        &ev.handle(),
+        journal.clone(),
+        slack_agent.channel_ids(),
+        loop_guard.clone(),
+    ) {
         Ok(s) => s,
         Err(e) => {
             error!("failed to load slack sender: {}", e.description());
@@ -122,7 +184,18 @@ fn main() {
 
     // cranking the event loop
     info!("starting up the relay");
-    match irc_cfg.run(&mut ev, irc_receive, &mut slack_send) {
+    let run = irc_cfg.run(
+        &mut ev,
+        irc_receive,
+        irc_outbox,
+        &mut slack_send,
+        journal,
+        conversations,
+        roster,
+        loop_guard,
+        filters,
+    );
+    match run {
         Ok(i) => i,
         Err(e) => {
             error!("Failed to run irc: {}", e.description());
@@ -134,10 +207,26 @@ fn main() {
 
 fn load_slack_receiver(
     cfg: slack_client::SlackCfg,
-    irc_stream: mpsc::Sender<message::SlackMsg>,
+    irc_stream: mpsc::Sender<()>,
+    irc_outbox: SharedOutbox,
+    journal: SharedJournal,
+    conversations: Conversations,
+    roster: SlackRoster,
+    loop_guard: LoopGuard,
+    filters: Filters,
 ) -> Result<(slack::RtmClient, SlackReceiver), errors::SlagErr> {
     let cli = slack::RtmClient::login(&cfg.secret.clone())?;
-    let slack_agent = SlackReceiver::new(cfg, irc_stream, &cli);
+    let slack_agent = SlackReceiver::new(
+        cfg,
+        irc_stream,
+        irc_outbox,
+        &cli,
+        journal,
+        conversations,
+        roster,
+        loop_guard,
+        filters,
+    );
     Ok((cli, slack_agent))
 }
 
@@ -145,8 +234,11 @@ fn load_slack_sink(
     slack_sink: mpsc::Receiver<message::SlackMsg>,
     cfg: slack_client::SlackCfg,
     handle: &Handle,
+    journal: SharedJournal,
+    channel_ids: HashMap<String, String>,
+    loop_guard: LoopGuard,
 ) -> Result<SlackSender, SlagErr> {
-    SlackSender::new(slack_sink, cfg, handle)
+    SlackSender::new(slack_sink, cfg, handle, journal, channel_ids, loop_guard)
 }
 
 fn get_config(path: Option<&str>) -> Result<cfg::Cfg, errors::SlagErr> {
@@ -1,17 +1,60 @@
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum SlackMsg {
     OutMsg(PrivMsg),
     ActionMsg(PrivMsg),
+    NoticeMsg(PrivMsg),
     StatusMsg(PrivMsg),
+    // a private message, chan is the recipient
+    DirectMsg(PrivMsg),
+    // a command for the relay from slack, never relayed
+    CommandMsg(PrivMsg),
+    // a topic change, nick is who changed it
+    TopicMsg(PrivMsg),
+    // who is in an IRC channel, for the presence view on slack
+    PresenceMsg(PrivMsg),
 }
 
-#[derive(Debug)]
+impl SlackMsg {
+    pub fn priv_msg(&self) -> &PrivMsg {
+        match *self {
+            SlackMsg::OutMsg(ref m)
+            | SlackMsg::ActionMsg(ref m)
+            | SlackMsg::NoticeMsg(ref m)
+            | SlackMsg::StatusMsg(ref m)
+            | SlackMsg::DirectMsg(ref m)
+            | SlackMsg::CommandMsg(ref m)
+            | SlackMsg::TopicMsg(ref m)
+            | SlackMsg::PresenceMsg(ref m) => m,
+        }
+    }
+
+    pub fn journal_id(&self) -> Option<u64> {
+        self.priv_msg().journal_id
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct PrivMsg {
     pub nick: String,
     pub chan: String,
     pub msg: String,
+    // services account of the IRC sender, if the server tags messages with it
+    #[serde(default)]
+    pub account: Option<String>,
+    // RFC 3339 time at which the IRC server received the message
+    #[serde(default)]
+    pub time: Option<String>,
+    // id the IRC server gave the message, if it tags messages with one
+    #[serde(default)]
+    pub msgid: Option<String>,
+    // id of the slack sender
+    #[serde(default)]
+    pub user_id: Option<String>,
+    // set once the message has been recorded in the journal
+    #[serde(default)]
+    pub journal_id: Option<u64>,
 }
 
 impl fmt::Display for PrivMsg {
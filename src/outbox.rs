@@ -0,0 +1,291 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use journal::{now, Direction, SharedJournal};
+use message::{PrivMsg, SlackMsg};
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct OutboxCfg {
+    /// Maximum number of messages held per direction.
+    capacity: Option<usize>,
+    /// Messages older than this many seconds are summarised instead of replayed.
+    max_age: Option<u64>,
+}
+
+const DEFAULT_CAPACITY: usize = 512;
+const DEFAULT_MAX_AGE: u64 = 600;
+
+impl OutboxCfg {
+    pub fn irc_outbox(&self, journal: SharedJournal) -> Outbox {
+        Outbox::new(self.capacity(), self.max_age(), Direction::ToIrc, journal)
+    }
+
+    pub fn slack_outbox(&self, journal: SharedJournal) -> Outbox {
+        Outbox::new(self.capacity(), self.max_age(), Direction::ToSlack, journal)
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity.unwrap_or(DEFAULT_CAPACITY)
+    }
+
+    fn max_age(&self) -> u64 {
+        self.max_age.unwrap_or(DEFAULT_MAX_AGE)
+    }
+}
+
+#[derive(Debug)]
+struct Queued {
+    queued_at: u64,
+    msg: SlackMsg,
+}
+
+pub type SharedOutbox = Arc<Mutex<Outbox>>;
+
+/// A bounded queue for messages that couldn't be delivered yet. The journal is
+/// its disk backing: it's refilled from the journal's undelivered entries on
+/// start, so it only survives restarts if there's a journal.
+pub struct Outbox {
+    queue: VecDeque<Queued>,
+    capacity: usize,
+    max_age: u64,
+    // dropped and summarised messages are acknowledged here
+    journal: SharedJournal,
+}
+
+impl Outbox {
+    /// An outbox holding what was left undelivered in a direction by a
+    /// previous run.
+    pub fn new(capacity: usize, max_age: u64, dir: Direction, journal: SharedJournal) -> Outbox {
+        let pending = journal.lock().unwrap().pending(dir);
+        let mut outbox = Outbox {
+            queue: VecDeque::new(),
+            capacity: capacity,
+            max_age: max_age,
+            journal: journal,
+        };
+        for (queued_at, msg) in pending {
+            outbox.queue.push_back(Queued {
+                queued_at: queued_at,
+                msg: msg,
+            });
+        }
+        while outbox.queue.len() > capacity {
+            if let Some(dropped) = outbox.queue.pop_front() {
+                error!("outbox full, dropping oldest message {:?}", dropped.msg);
+                outbox.journal.lock().unwrap().ack(dropped.msg.journal_id());
+            }
+        }
+        outbox
+    }
+
+    pub fn shared(self) -> SharedOutbox {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn push(&mut self, msg: SlackMsg) {
+        if self.queue.len() >= self.capacity {
+            if let Some(dropped) = self.queue.pop_front() {
+                error!("outbox full, dropping oldest message {:?}", dropped.msg);
+                self.journal.lock().unwrap().ack(dropped.msg.journal_id());
+            }
+        }
+        self.queue.push_back(Queued {
+            queued_at: now(),
+            msg: msg,
+        });
+    }
+
+    /// The message that's next in line.
+    pub fn front(&self) -> Option<&SlackMsg> {
+        self.queue.front().map(|q| &q.msg)
+    }
+
+    pub fn pop(&mut self) -> Option<SlackMsg> {
+        self.queue.pop_front().map(|q| q.msg)
+    }
+
+    /// Puts messages that have to be sent again in front of the rest, in the
+    /// order given.
+    pub fn requeue(&mut self, msgs: Vec<SlackMsg>) {
+        let queued_at = now();
+        for msg in msgs.into_iter().rev() {
+            self.queue.push_front(Queued {
+                queued_at: queued_at,
+                msg: msg,
+            });
+        }
+    }
+
+    /// Replaces the messages that are older than the configured age by a status
+    /// message per channel, which go first.
+    pub fn expire(&mut self) {
+        let cutoff = now().saturating_sub(self.max_age);
+        if self.queue.iter().all(|q| q.queued_at >= cutoff) {
+            return;
+        }
+        let mut stale: Vec<(String, Vec<String>)> = vec![];
+        let mut stale_idx: HashMap<String, usize> = HashMap::new();
+        let mut fresh = VecDeque::new();
+
+        let mut journal = self.journal.lock().unwrap();
+        for queued in self.queue.drain(..) {
+            if queued.queued_at >= cutoff {
+                fresh.push_back(queued);
+                continue;
+            }
+            journal.ack(queued.msg.journal_id());
+            let PrivMsg { nick, chan, .. } = match queued.msg {
+                SlackMsg::OutMsg(m) | SlackMsg::ActionMsg(m) | SlackMsg::NoticeMsg(m) => m,
+                // stale status messages carry no information worth summarising
+                SlackMsg::StatusMsg(_) | SlackMsg::CommandMsg(_) => continue,
+                // topics and presence are kept up to date separately
+                SlackMsg::TopicMsg(_) | SlackMsg::PresenceMsg(_) => continue,
+                // there's no channel to summarise private messages in
+                SlackMsg::DirectMsg(_) => continue,
+            };
+            let idx = *stale_idx.entry(chan.clone()).or_insert_with(|| {
+                stale.push((chan, vec![]));
+                stale.len() - 1
+            });
+            stale[idx].1.push(nick);
+        }
+        drop(journal);
+
+        let queued_at = now();
+        self.queue = stale
+            .into_iter()
+            .map(|(chan, nicks)| Queued {
+                queued_at: queued_at,
+                msg: SlackMsg::StatusMsg(summarise(chan, nicks)),
+            })
+            .chain(fresh)
+            .collect();
+    }
+
+    /// Takes the messages that `ready` accepts out of the outbox, in order and with
+    /// stale ones summarised as by `expire`. The others stay queued.
+    pub fn drain<F: Fn(&SlackMsg) -> bool>(&mut self, ready: F) -> Vec<SlackMsg> {
+        self.expire();
+        let (taken, kept): (VecDeque<Queued>, VecDeque<Queued>) =
+            self.queue.drain(..).partition(|q| ready(&q.msg));
+        self.queue = kept;
+        taken.into_iter().map(|q| q.msg).collect()
+    }
+}
+
+fn summarise(chan: String, mut nicks: Vec<String>) -> PrivMsg {
+    let count = nicks.len();
+    nicks.sort();
+    nicks.dedup();
+    PrivMsg {
+        chan: chan,
+        nick: "".to_string(),
+        msg: format!(
+            "! {} messages from {} were not relayed while disconnected !",
+            count,
+            nicks.join(", ")
+        ),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use journal::Journal;
+    use journal::tests::{msg, TempDir};
+
+    use super::*;
+
+    fn outbox(capacity: usize) -> Outbox {
+        Outbox::new(capacity, 600, Direction::ToIrc, Journal::disabled().shared())
+    }
+
+    fn texts(msgs: Vec<SlackMsg>) -> Vec<String> {
+        msgs.iter().map(|m| m.priv_msg().msg.clone()).collect()
+    }
+
+    fn to(chan: &str, text: &str) -> SlackMsg {
+        let mut m = msg(text);
+        if let SlackMsg::OutMsg(ref mut p) = m {
+            p.chan = chan.to_owned();
+        }
+        m
+    }
+
+    #[test]
+    fn drops_the_oldest_messages_when_full() {
+        let mut outbox = outbox(2);
+        for text in &["one", "two", "three"] {
+            outbox.push(msg(text));
+        }
+        assert_eq!(texts(outbox.drain(|_| true)), vec!["two", "three"]);
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn requeues_in_front_in_order() {
+        let mut outbox = outbox(8);
+        outbox.push(msg("three"));
+        outbox.requeue(vec![msg("one"), msg("two")]);
+        assert_eq!(outbox.front().map(|m| m.priv_msg().msg.as_str()), Some("one"));
+        assert_eq!(texts(outbox.drain(|_| true)), vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn keeps_what_is_not_ready_in_order() {
+        let mut outbox = outbox(8);
+        outbox.push(to("#a", "one"));
+        outbox.push(to("#b", "two"));
+        outbox.push(to("#a", "three"));
+        outbox.push(to("#b", "four"));
+        let ready = outbox.drain(|m| m.priv_msg().chan == "#b");
+        assert_eq!(texts(ready), vec!["two", "four"]);
+        assert_eq!(texts(outbox.drain(|_| true)), vec!["one", "three"]);
+    }
+
+    #[test]
+    fn summarises_stale_messages() {
+        let mut outbox = outbox(8);
+        outbox.push(to("#a", "one"));
+        outbox.push(msg("two"));
+        outbox.push(to("#a", "three"));
+        outbox.push(to("#b", "four"));
+        for queued in outbox.queue.iter_mut().take(3) {
+            queued.queued_at = 0;
+        }
+        if let SlackMsg::OutMsg(ref mut p) = outbox.queue[2].msg {
+            p.nick = "bob".to_owned();
+        }
+        assert_eq!(
+            texts(outbox.drain(|_| true)),
+            vec![
+                "! 2 messages from alice, bob were not relayed while disconnected !",
+                "! 1 messages from alice were not relayed while disconnected !",
+                "four",
+            ]
+        );
+    }
+
+    #[test]
+    fn is_refilled_from_the_journal() {
+        let dir = TempDir::new("outbox");
+        let journal = dir.journal(None).shared();
+        for text in &["one", "two", "three"] {
+            let mut m = msg(text);
+            journal.lock().unwrap().record(Direction::ToIrc, &mut m);
+        }
+        let mut m = msg("elsewhere");
+        journal.lock().unwrap().record(Direction::ToSlack, &mut m);
+
+        // the oldest message doesn't fit and is given up on
+        let mut outbox = Outbox::new(2, 600, Direction::ToIrc, journal.clone());
+        assert_eq!(journal.lock().unwrap().pending(Direction::ToIrc).len(), 2);
+        let replayed = outbox.drain(|_| true);
+        assert_eq!(texts(replayed.clone()), vec!["two", "three"]);
+        assert!(replayed.iter().all(|m| m.journal_id().is_some()));
+    }
+}
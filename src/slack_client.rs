@@ -1,19 +1,38 @@
 use slack_api;
+use slack_api::requests::SlackWebRequestSender;
 use slack;
 use slack::Event;
 use message;
 
 use errors::SlagErr;
+use journal::{Direction, SharedJournal};
+use commands::{Command, SlackRoster};
+use direct::Conversations;
+use loops::LoopGuard;
+use filters::{self, Filters, Verdict};
+use webhook::{self, PostError, Webhook};
+use outbox::SharedOutbox;
 
-use futures::sync::mpsc::{Receiver, Sender};
-use futures::Stream;
-use futures::Future;
+use futures::sync::mpsc::{self, Receiver, Sender, UnboundedSender};
+use futures::{future, Future, Stream};
+
+use chrono::{DateTime, Utc};
+use serde_json;
 
 use tokio_core::reactor;
+use tokio_timer::Timer;
 
-use slack_hook::{Parse, Payload as SlackPayload, PayloadBuilder, Slack};
+use slack_hook::{Parse, Payload as SlackPayload, PayloadBuilder};
 
+use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
 
 use message::{PrivMsg, SlackMsg};
 
@@ -21,16 +40,119 @@ use message::{PrivMsg, SlackMsg};
 pub struct SlackCfg {
     pub secret: String,
     pub hook_url: String,
+    /// Maximum number of messages per channel to relay after a reconnect.
+    pub backlog_limit: Option<u32>,
+    pub retry: Option<RetryCfg>,
     #[serde(skip)]
     pub channels: HashMap<String, String>,
 }
 
+const DEFAULT_BACKLOG_LIMIT: u32 = 50;
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct RetryCfg {
+    /// Number of attempts before a message is given up on.
+    max_attempts: Option<u32>,
+    /// Longest delay between two attempts, in seconds.
+    max_delay: Option<u64>,
+    /// File that messages which couldn't be delivered are appended to.
+    dead_letter_path: Option<PathBuf>,
+}
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_MAX_DELAY: u64 = 300;
+
+impl RetryCfg {
+    fn max_attempts(&self) -> u32 {
+        self.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS)
+    }
+
+    // 1, 2, 4, ... seconds, capped at max_delay
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max_delay = self.max_delay.unwrap_or(DEFAULT_MAX_DELAY);
+        let delay = (2 as u64).checked_pow(attempt - 1).unwrap_or(max_delay);
+        Duration::from_secs(cmp::min(delay, max_delay))
+    }
+
+    fn dead_letter(&self, body: &str, err: &PostError) {
+        error!("giving up on sending to slack after error {}: {}", err, body);
+        let path = match self.dead_letter_path {
+            Some(ref p) => p,
+            None => return,
+        };
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| writeln!(f, "{}", body));
+        if let Err(e) = res {
+            error!("failed to write to dead letter log {}: {}", path.display(), e);
+        }
+    }
+}
+
 pub struct SlackReceiver {
-    irc_chan: Sender<message::SlackMsg>,
+    // wakes IRC up when there are messages for it in irc_outbox
+    irc_chan: Sender<()>,
+    irc_outbox: SharedOutbox,
     cfg: SlackCfg,
     slack_nick_mappings: HashMap<String, String>,
     slack_channel_mappings: HashMap<String, String>,
-    first_msg: bool,
+    // latest message timestamp seen per slack channel id, kept across reconnects
+    last_seen_ts: HashMap<String, SlackTs>,
+    // when the relay first connected, the start for channels seen later on
+    first_connected: Option<SlackTs>,
+    journal: SharedJournal,
+    conversations: Conversations,
+    roster: SlackRoster,
+    loop_guard: LoopGuard,
+    filters: Filters,
+}
+
+/// A slack message timestamp, e.g. `1512085950.000216`, split into seconds and
+/// the sequence part so that timestamps can be ordered without float rounding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SlackTs(u64, u64);
+
+impl SlackTs {
+    pub fn parse(ts: &str) -> Option<SlackTs> {
+        let mut parts = ts.splitn(2, '.');
+        let secs = parts.next()?.parse().ok()?;
+        let seq = match parts.next() {
+            // a fraction of a second, i.e. `.5` is `.500000`
+            Some(seq) if seq.len() <= 6 && seq.bytes().all(|b| b.is_ascii_digit()) => {
+                seq.parse::<u64>().ok()? * 10u64.pow(6 - seq.len() as u32)
+            }
+            Some(_) => return None,
+            None => 0,
+        };
+        Some(SlackTs(secs, seq))
+    }
+}
+
+impl fmt::Display for SlackTs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:06}", self.0, self.1)
+    }
+}
+
+// Messages with timestamps that can't be made sense of are let through.
+fn is_new(last_seen: &mut HashMap<String, SlackTs>, chan_id: &str, ts: &str) -> bool {
+    let ts = match SlackTs::parse(ts) {
+        Some(ts) => ts,
+        None => {
+            warn!("unparseable slack timestamp '{}'", ts);
+            return true;
+        }
+    };
+    if let Some(last) = last_seen.get(chan_id) {
+        if ts <= *last {
+            debug!("dropping already seen message {:?} in {}", ts, chan_id);
+            return false;
+        }
+    }
+    last_seen.insert(chan_id.to_owned(), ts);
+    true
 }
 
 fn unwrap_chan_mapping(chan: &slack_api::Channel) -> Option<(String, String)> {
@@ -48,10 +170,19 @@ fn unwrap_user_mapping(usr: &slack_api::User) -> Option<(String, String)> {
 impl SlackReceiver {
     pub fn new(
         cfg: SlackCfg,
-        irc_chan: Sender<message::SlackMsg>,
+        irc_chan: Sender<()>,
+        irc_outbox: SharedOutbox,
         cli: &slack::RtmClient,
+        journal: SharedJournal,
+        conversations: Conversations,
+        roster: SlackRoster,
+        loop_guard: LoopGuard,
+        filters: Filters,
     ) -> SlackReceiver {
         let resp = cli.start_response();
+        if let Some(id) = resp.slf.as_ref().and_then(|s| s.id.as_ref()) {
+            loop_guard.set_own_user(id);
+        }
         let channels = resp.channels
             .as_ref()
             .unwrap_or(&vec![])
@@ -64,33 +195,60 @@ impl SlackReceiver {
             .iter()
             .filter_map(unwrap_user_mapping)
             .collect();
+        for chan in resp.channels.as_ref().unwrap_or(&vec![]) {
+            let topic = chan.topic.as_ref().and_then(|t| t.value.as_ref());
+            let irc_chan = chan.name.as_ref().and_then(|n| cfg.channels.get(n));
+            if let (Some(topic), Some(irc_chan)) = (topic, irc_chan) {
+                roster.set_topic(irc_chan, &unescape(topic));
+            }
+        }
 
         SlackReceiver {
             irc_chan: irc_chan,
+            irc_outbox: irc_outbox,
             cfg: cfg,
             slack_nick_mappings: nicks,
             slack_channel_mappings: channels,
-            first_msg: true,
+            last_seen_ts: HashMap::new(),
+            first_connected: None,
+            journal: journal,
+            conversations: conversations,
+            roster: roster,
+            loop_guard: loop_guard,
+            filters: filters,
         }
     }
 
+    /// Slack channel ids by name.
+    pub fn channel_ids(&self) -> HashMap<String, String> {
+        self.slack_channel_mappings
+            .iter()
+            .map(|(id, name)| (name.clone(), id.clone()))
+            .collect()
+    }
+
     // hacky shit, pls replace
     fn split_into_multiple(msg: PrivMsg) -> Vec<PrivMsg> {
-        let PrivMsg{nick, chan, msg} = msg;
-        msg
+        msg.msg
             .split('\n')
             .map(|chunk| PrivMsg {
-                nick: nick.clone(),
-                chan: chan.clone(),
                 msg: chunk.to_owned(),
+                ..msg.clone()
             })
             .collect()
     }
 
-    fn send_irc_msg(&mut self, msg: SlackMsg) {
-        if let Err(e) = self.irc_chan.try_send(msg) {
-            error!("Failed to send message to irc channel - {:?}", e);
-        };
+    // Messages wait in the outbox until IRC is connected and in its channels, so
+    // none are dropped when IRC can't keep up.
+    fn send_irc_msg(&mut self, mut msg: SlackMsg) {
+        self.journal.lock().unwrap().record(Direction::ToIrc, &mut msg);
+        self.irc_outbox.lock().unwrap().push(msg);
+        if let Err(e) = self.irc_chan.try_send(()) {
+            // when full, IRC has yet to wake up for earlier messages anyway
+            if !e.is_full() {
+                error!("Failed to wake up irc - {:?}", e);
+            }
+        }
     }
 
     fn handle_event(&mut self, event: slack::Event) {
@@ -100,26 +258,230 @@ impl SlackReceiver {
         }
     }
 
-    fn handle_msg(&mut self, slack_msg: slack::Message) {
-        self.slack_msg_to_privmsg(slack_msg).map(|m| {
-            for m in Self::split_into_multiple(m) {
+    // Sends every line of a multi-line message separately.
+    fn send_irc_lines(&mut self, msg: SlackMsg) {
+        match msg {
+            SlackMsg::OutMsg(m) => for m in Self::split_into_multiple(m) {
                 self.send_irc_msg(SlackMsg::OutMsg(m));
-            }
-        });
+            },
+            SlackMsg::ActionMsg(m) => for m in Self::split_into_multiple(m) {
+                self.send_irc_msg(SlackMsg::ActionMsg(m));
+            },
+            SlackMsg::DirectMsg(m) => for m in Self::split_into_multiple(m) {
+                self.send_irc_msg(SlackMsg::DirectMsg(m));
+            },
+            other => self.send_irc_msg(other),
+        }
     }
 
-    fn slack_msg_to_privmsg(&mut self, s_msg: slack::Message) -> Option<message::PrivMsg> {
-        // Dirty hax as the first message is always replayed.
-        if self.first_msg {
-            self.first_msg = false;
-            return None;
+    fn handle_msg(&mut self, slack_msg: slack::Message) {
+        self.relay(slack_msg, false);
+    }
+
+    // Everything from slack goes through here, whether it arrived live or was caught
+    // up on after a reconnect.
+    fn relay(&mut self, slack_msg: slack::Message, backlog: bool) {
+        if !self.is_relayable(&slack_msg) {
+            return;
+        }
+        let mut m = match self.slack_msg_to_privmsg(slack_msg) {
+            Some(m) => m,
+            None => return,
+        };
+        if !self.filter(&mut m) {
+            return;
+        }
+        match m {
+            // stale commands aren't worth answering
+            SlackMsg::CommandMsg(_) if backlog => return,
+            SlackMsg::OutMsg(ref mut p) | SlackMsg::ActionMsg(ref mut p) => {
+                if self.loop_guard.repeated(Direction::ToIrc, &p.chan, &p.msg) {
+                    return;
+                }
+                if backlog {
+                    p.msg = format!("[backlog] {}", p.msg);
+                } else {
+                    self.roster.spoke(&p.chan, &p.nick);
+                }
+            }
+            _ => (),
         }
+        self.send_irc_lines(m);
+    }
+
+    fn slack_msg_to_privmsg(&mut self, s_msg: slack::Message) -> Option<SlackMsg> {
         match s_msg {
-            slack::Message::Standard(m) => self.std_msg_to_priv(m),
+            slack::Message::Standard(ref m) if is_direct(&m.channel) => self.direct_msg_to_priv(
+                m.channel.clone(),
+                m.ts.clone(),
+                m.user.clone(),
+                m.text.clone(),
+            ),
+            slack::Message::Standard(m) => self.std_msg_to_priv(m).map(out_or_command),
+            // sent with /me
+            slack::Message::MeMessage(m) => self.me_msg_to_priv(m).map(SlackMsg::ActionMsg),
+            slack::Message::ChannelTopic(m) => self.topic_msg_to_priv(m),
+            // posts by integrations, including the relay's own webhook
+            slack::Message::BotMessage(_) => None,
             _ => None,
         }
     }
 
+    // Applies the content filters, telling the sender if their message is stopped.
+    // Returns whether the message should still be relayed.
+    fn filter(&self, msg: &mut SlackMsg) -> bool {
+        let (verdict, p) = match *msg {
+            SlackMsg::OutMsg(ref mut p) | SlackMsg::ActionMsg(ref mut p) => {
+                (self.filters.apply(Direction::ToIrc, &p.chan, &p.msg), p)
+            }
+            // addressed to a nick rather than a channel
+            SlackMsg::DirectMsg(ref mut p) => {
+                (self.filters.apply_direct(Direction::ToIrc, &p.msg), p)
+            }
+            _ => return true,
+        };
+        match verdict {
+            Verdict::Relay(text) => {
+                p.msg = text;
+                true
+            }
+            Verdict::Warn(rule) => {
+                self.warn_sender(&p.nick, &filters::warning(&rule));
+                false
+            }
+            Verdict::Drop(_) => false,
+        }
+    }
+
+    fn warn_sender(&self, nick: &str, text: &str) {
+        let client = match slack_api::requests::default_client() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("failed to create a client to warn {}: {}", nick, e);
+                return;
+            }
+        };
+        let chan = format!("@{}", nick);
+        let req = slack_api::chat::PostMessageRequest {
+            channel: &chan,
+            text: text,
+            ..Default::default()
+        };
+        if let Err(e) = slack_api::chat::post_message(&client, &self.cfg.secret, &req) {
+            warn!("failed to warn {} about a filtered message: {:?}", nick, e);
+        }
+    }
+
+    // Leaves out what the relay posted itself and what ignored users and bots posted.
+    fn is_relayable(&self, s_msg: &slack::Message) -> bool {
+        let (user, bot_id, username, text) = match *s_msg {
+            slack::Message::Standard(ref m) => (&m.user, &m.bot_id, &None, &m.text),
+            slack::Message::MeMessage(ref m) => (&m.user, &None, &None, &m.text),
+            slack::Message::BotMessage(ref m) => (&None, &m.bot_id, &m.username, &m.text),
+            _ => return true,
+        };
+        let user = user.as_ref().map(|u| u.as_str());
+        let bot_id = bot_id.as_ref().map(|b| b.as_str());
+        let username = username.as_ref().map(|n| n.as_str());
+        let name = user.and_then(|u| self.slack_nick_mappings.get(u)).map(|n| n.as_str());
+        let text = unescape(text.as_ref().map(|t| t.as_str()).unwrap_or(""));
+        !self.loop_guard.is_own_slack(user, bot_id, username, &text)
+            && !self.loop_guard.ignores_slack(&[user, name, bot_id, username])
+    }
+
+    // Calls a web API method from the RTM thread, where blocking is fine.
+    fn api_call(&self, method: &str, params: &[(&str, &str)]) -> Result<serde_json::Value, String> {
+        let client = slack_api::requests::default_client().map_err(|e| e.to_string())?;
+        let mut params = params.to_vec();
+        params.push(("token", &self.cfg.secret));
+        let url = format!("{}{}", webhook::API_URL, method);
+        let body = client.send(&url, &params[..]).map_err(|e| e.to_string())?;
+        let reply: serde_json::Value = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+        if reply["ok"] != true {
+            return Err(reply["error"].as_str().unwrap_or("unknown error").to_owned());
+        }
+        Ok(reply)
+    }
+
+    // Mapped channels that nothing was seen in yet are caught up on from the time
+    // the relay first connected. Returns whether this is the first connection, when
+    // there's nothing to catch up on.
+    fn seed_last_seen(&mut self) -> bool {
+        let first = self.first_connected.is_none();
+        let now = SlackTs(Utc::now().timestamp() as u64, 0);
+        let since = *self.first_connected.get_or_insert(now);
+        for (id, name) in &self.slack_channel_mappings {
+            if self.cfg.channels.contains_key(name) {
+                self.last_seen_ts.entry(id.clone()).or_insert(since);
+            }
+        }
+        first
+    }
+
+    // Relays everything that was posted in the mapped channels since the last message that
+    // was seen before the connection dropped.
+    fn catch_up(&mut self) {
+        let limit = self.cfg.backlog_limit.unwrap_or(DEFAULT_BACKLOG_LIMIT).to_string();
+        let to_fetch: Vec<(String, String)> = self.cfg
+            .channels
+            .keys()
+            .filter_map(|name| {
+                let id = self.slack_channel_mappings
+                    .iter()
+                    .find(|&(_, n)| n == name)
+                    .map(|(id, _)| id)?;
+                let oldest = self.last_seen_ts.get(id)?;
+                Some((id.clone(), oldest.to_string()))
+            })
+            .collect();
+
+        for (chan_id, oldest) in to_fetch {
+            let params = [
+                ("channel", chan_id.as_str()),
+                ("oldest", oldest.as_str()),
+                ("limit", limit.as_str()),
+            ];
+            let resp = match self.api_call("conversations.history", &params) {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("failed to fetch slack backlog for {}: {}", chan_id, e);
+                    continue;
+                }
+            };
+            if resp["has_more"] == true {
+                warn!(
+                    "more than {} messages missed in {}, relaying only the latest",
+                    limit, chan_id
+                );
+            }
+            // history is returned newest first
+            let mut missed: Vec<slack::Message> =
+                match serde_json::from_value(resp["messages"].clone()) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        error!("unexpected slack backlog for {}: {}", chan_id, e);
+                        continue;
+                    }
+                };
+            missed.reverse();
+            for msg in missed {
+                // the history doesn't say which channel it's from
+                let msg = match msg {
+                    slack::Message::Standard(mut m) => {
+                        m.channel = Some(chan_id.clone());
+                        slack::Message::Standard(m)
+                    }
+                    slack::Message::MeMessage(mut m) => {
+                        m.channel = Some(chan_id.clone());
+                        slack::Message::MeMessage(m)
+                    }
+                    _ => continue,
+                };
+                self.relay(msg, true);
+            }
+        }
+    }
+
     fn notify_of_disconnect(&mut self) {
         let status_messages: Vec<PrivMsg> = self.cfg
             .channels
@@ -128,6 +490,7 @@ impl SlackReceiver {
                 chan: chan.clone(),
                 msg: "! DISCONNECTED FROM SLACK !".to_owned(),
                 nick: "".to_string(),
+                ..Default::default()
             })
             .collect();
 
@@ -136,18 +499,88 @@ impl SlackReceiver {
         }
     }
 
+    // Slack replays the latest message of a channel after (re)connecting, so anything that
+    // isn't newer than the last message seen in that channel is dropped.
+    fn is_new_msg(&mut self, chan_id: &str, ts: &str) -> bool {
+        is_new(&mut self.last_seen_ts, chan_id, ts)
+    }
+
+    // Private messages to the relay, which aren't caught up on after reconnecting,
+    // though replays are dropped like in channels. Commands are answered to anyone,
+    // the rest is only relayed for users that opted in.
+    fn direct_msg_to_priv(
+        &mut self,
+        channel: Option<String>,
+        ts: Option<String>,
+        user: Option<String>,
+        text: Option<String>,
+    ) -> Option<SlackMsg> {
+        if let (Some(chan_id), Some(ts)) = (channel.as_ref(), ts.as_ref()) {
+            if let Some(since) = self.first_connected {
+                self.last_seen_ts.entry(chan_id.clone()).or_insert(since);
+            }
+            if !self.is_new_msg(chan_id, ts) {
+                return None;
+            }
+        }
+        let user = user?;
+        let nick = self.slack_nick_mappings.get(&user)?;
+        let msg = PrivMsg {
+            nick: nick.clone(),
+            msg: text?,
+            user_id: Some(user.clone()),
+            ..Default::default()
+        };
+        if Command::parse(&msg.msg).is_some() {
+            return Some(SlackMsg::CommandMsg(msg));
+        }
+        self.conversations.from_slack(msg).map(SlackMsg::DirectMsg)
+    }
+
     fn std_msg_to_priv(&mut self, std_msg: slack_api::MessageStandard) -> Option<message::PrivMsg> {
-        let nick = std_msg.user.map(|u| self.slack_nick_mappings.get(&u))??;
-        let slack_chan = std_msg
-            .channel
-            .map(|c| self.slack_channel_mappings.get(&c))??;
+        self.to_priv(std_msg.channel, std_msg.ts, std_msg.user, std_msg.text)
+    }
+
+    fn me_msg_to_priv(&mut self, me_msg: slack_api::MessageMeMessage) -> Option<message::PrivMsg> {
+        self.to_priv(me_msg.channel, me_msg.ts, me_msg.user, me_msg.text)
+    }
+
+    // Topic changes that IRC doesn't know about yet, which leaves out the ones
+    // the relay made itself.
+    fn topic_msg_to_priv(&mut self, m: slack_api::MessageChannelTopic) -> Option<SlackMsg> {
+        let pmsg = self.to_priv(m.channel, m.ts, m.user, m.topic)?;
+        let topic = unescape(&pmsg.msg);
+        if self.roster.topic(&pmsg.chan).as_ref() == Some(&topic) {
+            return None;
+        }
+        self.roster.set_topic(&pmsg.chan, &topic);
+        Some(SlackMsg::TopicMsg(PrivMsg { msg: topic, ..pmsg }))
+    }
+
+    fn to_priv(
+        &mut self,
+        channel: Option<String>,
+        ts: Option<String>,
+        user: Option<String>,
+        text: Option<String>,
+    ) -> Option<message::PrivMsg> {
+        if let (Some(chan_id), Some(ts)) = (channel.as_ref(), ts.as_ref()) {
+            if !self.is_new_msg(chan_id, ts) {
+                return None;
+            }
+        }
+        let user = user?;
+        let nick = self.slack_nick_mappings.get(&user)?;
+        let slack_chan = channel.map(|c| self.slack_channel_mappings.get(&c))??;
         let chan = self.cfg.channels.get(slack_chan)?;
-        let text = std_msg.text?;
+        let text = text?;
 
         Some(PrivMsg {
             nick: nick.clone(),
             chan: chan.clone(),
             msg: text,
+            user_id: Some(user.clone()),
+            ..Default::default()
         })
     }
 }
@@ -159,18 +592,61 @@ impl slack::EventHandler for SlackReceiver {
 
     fn on_close(&mut self, _: &slack::RtmClient) {
         warn!("disconnected from slack");
+        self.roster.set_connected(false);
         self.notify_of_disconnect();
     }
 
     fn on_connect(&mut self, _: &slack::RtmClient) {
         warn!("joined slack");
+        self.roster.set_connected(true);
+        if !self.seed_last_seen() {
+            self.catch_up();
+        }
     }
 }
 
 pub struct SlackSender {
     sink: Receiver<SlackMsg>,
     cfg: SlackCfg,
-    slack: Slack,
+    webhook: Webhook,
+    journal: SharedJournal,
+    // slack channel ids by name, for setting topics
+    channel_ids: HashMap<String, String>,
+    loop_guard: LoopGuard,
+}
+
+// Posts a payload, retrying with exponential backoff or the delay slack asks for
+// when rate limiting. Resolves once the payload is either delivered or given up on.
+fn deliver(
+    webhook: Rc<Webhook>,
+    timer: Timer,
+    retry: Rc<RetryCfg>,
+    body: String,
+    attempt: u32,
+) -> Box<Future<Item = (), Error = ()>> {
+    let post = webhook.post(body.clone());
+    Box::new(post.then(move |res| {
+        let err = match res {
+            Ok(()) => return Box::new(future::ok(())) as Box<Future<Item = (), Error = ()>>,
+            Err(e) => e,
+        };
+        if !err.is_retryable() || attempt >= retry.max_attempts() {
+            retry.dead_letter(&body, &err);
+            return Box::new(future::ok(()));
+        }
+        let delay = err.retry_after().unwrap_or_else(|| retry.backoff(attempt));
+        warn!(
+            "failed to send to slack ({}), retrying in {}s",
+            err,
+            delay.as_secs()
+        );
+        Box::new(
+            timer
+                .sleep(delay)
+                .map_err(|e| error!("failed to sleep: {}", e))
+                .and_then(move |_| deliver(webhook, timer, retry, body, attempt + 1)),
+        )
+    }))
 }
 
 impl SlackSender {
@@ -178,53 +654,160 @@ impl SlackSender {
         sink: Receiver<message::SlackMsg>,
         cfg: SlackCfg,
         handle: &reactor::Handle,
+        journal: SharedJournal,
+        channel_ids: HashMap<String, String>,
+        loop_guard: LoopGuard,
     ) -> Result<SlackSender, SlagErr> {
-        let slack = Slack::new(cfg.hook_url.as_str(), handle)?;
-        let SlackCfg {
-            channels,
-            hook_url,
-            secret,
-        } = cfg;
-        let inverted_chan_map = channels
-            .into_iter()
-            .map(|(slack_chan, irc_chan)| (irc_chan, slack_chan))
+        let webhook = Webhook::new(cfg.hook_url.as_str(), handle)?;
+        let inverted_chan_map = cfg.channels
+            .iter()
+            .map(|(slack_chan, irc_chan)| (irc_chan.clone(), slack_chan.clone()))
             .collect();
         let cfg = SlackCfg {
             channels: inverted_chan_map,
-            hook_url: hook_url,
-            secret: secret,
+            ..cfg
         };
         Ok(SlackSender {
             sink: sink,
             cfg: cfg,
-            slack: slack,
+            webhook: webhook,
+            journal: journal,
+            channel_ids: channel_ids,
+            loop_guard: loop_guard,
         })
     }
 
+    // Messages are delivered one at a time per channel so that retries don't
+    // reorder them, while different channels are delivered to concurrently.
     pub fn process(self, handle: &reactor::Handle) {
-        let SlackSender { sink, cfg, slack } = self;
-        let work = sink.map_err(|_| ())
-            .filter_map(move |m| match m {
+        let SlackSender { sink, cfg, webhook, journal, channel_ids, loop_guard } = self;
+        let webhook = Rc::new(webhook);
+        let retry = Rc::new(cfg.retry.clone().unwrap_or_default());
+        let timer = Timer::default();
+        let inner_handle = handle.clone();
+        let pinned = Rc::new(RefCell::new(HashMap::new()));
+        let mut queues: HashMap<String, UnboundedSender<(Option<u64>, String)>> = HashMap::new();
+
+        let work = sink.for_each(move |m| {
+            let m = match m {
+                SlackMsg::TopicMsg(pmsg) => {
+                    inner_handle.spawn(set_topic(webhook.clone(), &cfg, &channel_ids, pmsg));
+                    return Ok(());
+                }
+                SlackMsg::PresenceMsg(pmsg) => {
+                    let chan_id = cfg.channels.get(&pmsg.chan).and_then(|c| channel_ids.get(c));
+                    if let Some(chan_id) = chan_id {
+                        let update = update_presence(
+                            webhook.clone(),
+                            &cfg.secret,
+                            pinned.clone(),
+                            chan_id.clone(),
+                            pmsg.msg,
+                        );
+                        inner_handle.spawn(update);
+                    }
+                    return Ok(());
+                }
+                other => other,
+            };
+            let id = m.journal_id();
+            let chan = m.priv_msg().chan.clone();
+            match m {
+                SlackMsg::OutMsg(ref p)
+                | SlackMsg::ActionMsg(ref p)
+                | SlackMsg::NoticeMsg(ref p) => loop_guard.posted(&display_name(p), &p.msg),
+                _ => (),
+            }
+            let payload = match m {
                 SlackMsg::OutMsg(pmsg) => SlackSender::try_slack_msg_from_priv(&cfg, pmsg),
                 SlackMsg::ActionMsg(pmsg) => SlackSender::try_action_msg_from_priv(&cfg, pmsg),
-                _ => None,
-            })
-            .map(move |payload| {
-                slack
-                    .send(&payload)
-                    .map_err(|e| error!("failed to send to slack: {}", e.description()))
-            })
-            .buffered(1024)
-            .for_each(|_| Ok(()));
+                SlackMsg::NoticeMsg(pmsg) => SlackSender::try_notice_msg_from_priv(&cfg, pmsg),
+                SlackMsg::StatusMsg(pmsg) => SlackSender::try_status_msg_from_priv(&cfg, pmsg),
+                SlackMsg::DirectMsg(pmsg) => SlackSender::try_direct_msg_from_priv(pmsg),
+                // commands only go to IRC, topics and presence are handled above
+                SlackMsg::CommandMsg(_) | SlackMsg::TopicMsg(_) | SlackMsg::PresenceMsg(_) => None,
+            };
+            let body = match payload.map(|p| serde_json::to_string(&p)) {
+                Some(Ok(body)) => body,
+                Some(Err(e)) => {
+                    error!("failed to serialize slack payload: {}", e);
+                    journal.lock().unwrap().ack(id);
+                    return Ok(());
+                }
+                None => {
+                    journal.lock().unwrap().ack(id);
+                    return Ok(());
+                }
+            };
+
+            let queue = queues.entry(chan).or_insert_with(|| {
+                let (tx, rx) = mpsc::unbounded();
+                let webhook = webhook.clone();
+                let retry = retry.clone();
+                let timer = timer.clone();
+                let journal = journal.clone();
+                let worker = rx.for_each(move |(id, body)| {
+                    let journal = journal.clone();
+                    deliver(webhook.clone(), timer.clone(), retry.clone(), body, 1).then(
+                        move |_| {
+                            journal.lock().unwrap().ack(id);
+                            Ok(())
+                        },
+                    )
+                });
+                inner_handle.spawn(worker);
+                tx
+            });
+            if let Err(e) = queue.unbounded_send((id, body)) {
+                error!("failed to queue message for slack: {}", e);
+            }
+            Ok(())
+        });
         handle.spawn(work);
     }
 
     fn try_slack_msg_from_priv(cfg: &SlackCfg, pmsg: PrivMsg) -> Option<SlackPayload> {
         let out_chan = cfg.channels.get(&pmsg.chan)?;
         PayloadBuilder::new()
+            .text(format!("{}{}", delay_prefix(&pmsg), pmsg.msg))
+            .channel(out_chan.clone())
+            .username(display_name(&pmsg))
+            .parse(Parse::Full)
+            .build()
+            .ok()
+    }
+
+    fn try_status_msg_from_priv(cfg: &SlackCfg, pmsg: PrivMsg) -> Option<SlackPayload> {
+        let out_chan = cfg.channels.get(&pmsg.chan)?;
+        PayloadBuilder::new()
+            .text(pmsg.msg)
+            .channel(out_chan.clone())
+            .build()
+            .ok()
+    }
+
+    // The webhook delivers to a user's direct messages when the channel is `@name`.
+    fn try_direct_msg_from_priv(pmsg: PrivMsg) -> Option<SlackPayload> {
+        let payload = PayloadBuilder::new()
             .text(pmsg.msg.clone())
+            .channel(format!("@{}", pmsg.chan))
+            .parse(Parse::Full);
+        // answers to commands come from the relay itself
+        let payload = if pmsg.nick.is_empty() {
+            payload
+        } else {
+            payload.username(display_name(&pmsg))
+        };
+        payload.build().ok()
+    }
+
+    // Notices are quoted so they stand out from regular messages.
+    fn try_notice_msg_from_priv(cfg: &SlackCfg, pmsg: PrivMsg) -> Option<SlackPayload> {
+        let out_chan = cfg.channels.get(&pmsg.chan)?;
+        PayloadBuilder::new()
+            .text(format!("{}> *notice:* {}", delay_prefix(&pmsg), pmsg.msg))
             .channel(out_chan.clone())
-            .username(pmsg.nick.clone())
+            .username(display_name(&pmsg))
             .parse(Parse::Full)
             .build()
             .ok()
@@ -234,11 +817,487 @@ impl SlackSender {
         use slack_hook::SlackTextContent::Text;
         let out_chan = cfg.channels.get(&pmsg.chan)?;
         PayloadBuilder::new()
-            .text(vec![Text("_".into()), Text(pmsg.msg.into()), Text("_".into())].as_slice())
+            .text(vec![
+                Text(delay_prefix(&pmsg).into()),
+                Text("_".into()),
+                Text(pmsg.msg.clone().into()),
+                Text("_".into()),
+            ].as_slice())
             .channel(out_chan.clone())
-            .username(pmsg.nick.clone())
+            .username(display_name(&pmsg))
             .parse(Parse::Full)
             .build()
             .ok()
     }
 }
+
+// Topics are set through the web API, as webhooks can't. If the relay isn't allowed to,
+// the change is announced in the channel instead.
+fn set_topic(
+    webhook: Rc<Webhook>,
+    cfg: &SlackCfg,
+    channel_ids: &HashMap<String, String>,
+    pmsg: PrivMsg,
+) -> Box<Future<Item = (), Error = ()>> {
+    let chan_id = match cfg.channels.get(&pmsg.chan).and_then(|c| channel_ids.get(c)) {
+        Some(id) => id.clone(),
+        None => return Box::new(future::ok(())),
+    };
+    let text = if pmsg.nick.is_empty() {
+        format!("! the IRC topic is: {} !", pmsg.msg)
+    } else {
+        format!("! {} changed the IRC topic to: {} !", pmsg.nick, pmsg.msg)
+    };
+    let status = PrivMsg { msg: text, ..pmsg.clone() };
+    let fallback = SlackSender::try_status_msg_from_priv(cfg, status)
+        .and_then(|p| serde_json::to_string(&p).ok());
+    let params = [("channel", chan_id.as_str()), ("topic", pmsg.msg.as_str())];
+    let call = webhook.call("conversations.setTopic", &cfg.secret, &params);
+    Box::new(call.map(|_| ()).or_else(move |e| {
+        warn!("failed to set the slack topic, announcing it instead: {}", e);
+        match fallback {
+            Some(body) => Box::new(webhook.post(body).map_err(|e| {
+                error!("failed to announce topic on slack: {}", e)
+            })) as Box<Future<Item = (), Error = ()>>,
+            None => Box::new(future::ok(())),
+        }
+    }))
+}
+
+// The presence view is a message pinned to the channel that's edited as people
+// come and go. After a restart, the view pinned by the previous run is looked up
+// again. If editing it fails, e.g. after it was deleted, it's unpinned and a new
+// one is posted.
+fn update_presence(
+    webhook: Rc<Webhook>,
+    secret: &str,
+    pinned: Rc<RefCell<HashMap<String, String>>>,
+    chan_id: String,
+    text: String,
+) -> Box<Future<Item = (), Error = ()>> {
+    let known = pinned.borrow().get(&chan_id).cloned();
+    let existing: Box<Future<Item = Option<String>, Error = PostError>> = match known {
+        Some(ts) => Box::new(future::ok(Some(ts))),
+        None => find_presence(webhook.clone(), secret.to_owned(), chan_id.clone()),
+    };
+    let (secret, chan) = (secret.to_owned(), chan_id.clone());
+    let remembered = pinned.clone();
+    let update = existing.and_then(move |existing| -> Box<Future<Item = (), Error = PostError>> {
+        let ts = match existing {
+            Some(ts) => ts,
+            None => return post_presence(webhook, secret, remembered, chan, None, text),
+        };
+        remembered.borrow_mut().insert(chan.clone(), ts.clone());
+        let edit = {
+            let params = [
+                ("channel", chan.as_str()),
+                ("ts", ts.as_str()),
+                ("text", text.as_str()),
+            ];
+            webhook.call("chat.update", &secret, &params)
+        };
+        Box::new(edit.map(|_| ()).or_else(move |e| {
+            warn!("failed to edit the IRC presence in {}, replacing it: {}", chan, e);
+            post_presence(webhook, secret, remembered, chan, Some(ts), text)
+        }))
+    });
+    Box::new(update.map_err(move |e| {
+        warn!("failed to update the IRC presence in {}: {}", chan_id, e);
+        pinned.borrow_mut().remove(&chan_id);
+    }))
+}
+
+// Posts and pins a presence view, unpinning the one it replaces.
+fn post_presence(
+    webhook: Rc<Webhook>,
+    secret: String,
+    pinned: Rc<RefCell<HashMap<String, String>>>,
+    chan_id: String,
+    replaces: Option<String>,
+    text: String,
+) -> Box<Future<Item = (), Error = PostError>> {
+    let unpinned: Box<Future<Item = (), Error = PostError>> = match replaces {
+        Some(ts) => unpin(&webhook, &secret, &chan_id, &ts),
+        None => Box::new(future::ok(())),
+    };
+    let post = unpinned.and_then(move |_| {
+        let posted = {
+            let params = [
+                ("channel", chan_id.as_str()),
+                ("text", text.as_str()),
+                ("username", "IRC"),
+            ];
+            webhook.call("chat.postMessage", &secret, &params)
+        };
+        posted.and_then(move |reply| {
+            let ts = reply["ts"].as_str().unwrap_or_default().to_owned();
+            pinned.borrow_mut().insert(chan_id.clone(), ts.clone());
+            let params = [("channel", chan_id.as_str()), ("timestamp", ts.as_str())];
+            webhook.call("pins.add", &secret, &params)
+        })
+    });
+    Box::new(post.map(|_| ()))
+}
+
+// Finds the presence view pinned by an earlier run, if any. Only pins posted by
+// the relay itself count, as others' messages could look the same. Should there
+// be more than one, the older ones are unpinned.
+fn find_presence(
+    webhook: Rc<Webhook>,
+    secret: String,
+    chan_id: String,
+) -> Box<Future<Item = Option<String>, Error = PostError>> {
+    let own = webhook.call("auth.test", &secret, &[]).map(|reply| {
+        let id = |key: &str| reply[key].as_str().map(|s| s.to_owned());
+        (id("user_id"), id("bot_id"))
+    });
+    let find = own.and_then(move |own| {
+        let listed = webhook.call("pins.list", &secret, &[("channel", chan_id.as_str())]);
+        listed.and_then(move |reply| {
+            let mut found: Vec<String> = reply["items"]
+                .as_array()
+                .into_iter()
+                .flat_map(|items| items.iter())
+                .map(|item| &item["message"])
+                .filter(|msg| is_own(&own, msg))
+                .filter(|msg| msg["text"].as_str().map(is_presence).unwrap_or(false))
+                .filter_map(|msg| msg["ts"].as_str().map(|ts| ts.to_owned()))
+                .collect();
+            found.sort_by_key(|ts| SlackTs::parse(ts));
+            let latest = found.pop();
+            let stale: Vec<_> = found
+                .iter()
+                .map(|ts| unpin(&webhook, &secret, &chan_id, ts))
+                .collect();
+            future::join_all(stale).map(move |_| latest)
+        })
+    });
+    Box::new(find)
+}
+
+// Whether a message was posted by the relay, given its user and bot ids.
+fn is_own(own: &(Option<String>, Option<String>), msg: &serde_json::Value) -> bool {
+    let posted_by = |key: &str, id: &Option<String>| match (msg[key].as_str(), id.as_ref()) {
+        (Some(by), Some(id)) => by == id,
+        _ => false,
+    };
+    posted_by("user", &own.0) || posted_by("bot_id", &own.1)
+}
+
+// Failing to unpin a message isn't worth giving up on the presence view for.
+fn unpin(
+    webhook: &Webhook,
+    secret: &str,
+    chan_id: &str,
+    ts: &str,
+) -> Box<Future<Item = (), Error = PostError>> {
+    let removed = webhook.call("pins.remove", secret, &[("channel", chan_id), ("timestamp", ts)]);
+    let chan_id = chan_id.to_owned();
+    Box::new(removed.then(move |res| {
+        if let Err(e) = res {
+            warn!("failed to unpin an old IRC presence in {}: {}", chan_id, e);
+        }
+        Ok(())
+    }))
+}
+
+// e.g. `3 on IRC: @alice, +bob, carol`
+fn is_presence(text: &str) -> bool {
+    let mut parts = text.splitn(2, " on IRC:");
+    match (parts.next(), parts.next()) {
+        (Some(count), Some(_)) => count.parse::<usize>().is_ok(),
+        _ => false,
+    }
+}
+
+// Slack escapes these in message text.
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn out_or_command(msg: PrivMsg) -> SlackMsg {
+    if Command::parse(&msg.msg).is_some() {
+        SlackMsg::CommandMsg(msg)
+    } else {
+        SlackMsg::OutMsg(msg)
+    }
+}
+
+// Direct message channel ids start with a D.
+fn is_direct(channel: &Option<String>) -> bool {
+    channel.as_ref().map(|c| c.starts_with('D')).unwrap_or(false)
+}
+
+// messages that reach slack later than this after being sent on IRC, e.g. from a
+// bouncer's playback, are shown with their original time
+const DELAY_THRESHOLD_SECS: i64 = 60;
+
+fn delay_prefix(pmsg: &PrivMsg) -> String {
+    let sent = match pmsg.time.as_ref().and_then(|t| DateTime::parse_from_rfc3339(t).ok()) {
+        Some(t) => t.with_timezone(&Utc),
+        None => return String::new(),
+    };
+    if Utc::now().signed_duration_since(sent).num_seconds() < DELAY_THRESHOLD_SECS {
+        return String::new();
+    }
+    format!("[{}] ", sent.format("%Y-%m-%d %H:%M:%S UTC"))
+}
+
+// Shows the services account next to the nick when they differ.
+fn display_name(pmsg: &PrivMsg) -> String {
+    match pmsg.account {
+        Some(ref account) if !account.eq_ignore_ascii_case(&pmsg.nick) => {
+            format!("{} ({})", pmsg.nick, account)
+        }
+        _ => pmsg.nick.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::Instant;
+
+    use hyper::{self, Chunk, StatusCode};
+    use hyper::header::RetryAfter;
+    use hyper::server::{Http, Request, Response, Service};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    use journal::Journal;
+    use loops::LoopCfg;
+
+    use super::*;
+
+    // A webhook stand-in that answers with the given replies in turn and with 200
+    // after that, recording when each post came in and what it said.
+    #[derive(Clone)]
+    struct Stub {
+        replies: Rc<RefCell<VecDeque<Response>>>,
+        posts: Rc<RefCell<Vec<(Instant, String)>>>,
+    }
+
+    impl Service for Stub {
+        type Request = Request;
+        type Response = Response;
+        type Error = hyper::Error;
+        type Future = Box<Future<Item = Response, Error = hyper::Error>>;
+
+        fn call(&self, req: Request) -> Self::Future {
+            let stub = self.clone();
+            Box::new(req.body().concat2().map(move |body| {
+                let body = String::from_utf8_lossy(&body).into_owned();
+                stub.posts.borrow_mut().push((Instant::now(), body));
+                stub.replies.borrow_mut().pop_front().unwrap_or_else(Response::new)
+            }))
+        }
+    }
+
+    // Serves the stub on a local port, returning its URL and the posts it gets.
+    fn serve(core: &Core, replies: Vec<Response>) -> (String, Rc<RefCell<Vec<(Instant, String)>>>) {
+        let stub = Stub {
+            replies: Rc::new(RefCell::new(replies.into_iter().collect())),
+            posts: Rc::new(RefCell::new(vec![])),
+        };
+        let posts = stub.posts.clone();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (http, conn_handle) = (Http::<Chunk>::new(), handle.clone());
+        let server = listener
+            .incoming()
+            .for_each(move |(sock, _)| {
+                let conn = http.serve_connection(sock, stub.clone());
+                conn_handle.spawn(conn.map_err(|e| error!("stub connection failed: {}", e)));
+                Ok(())
+            })
+            .map_err(|e| error!("stub failed: {}", e));
+        handle.spawn(server);
+        (url, posts)
+    }
+
+    fn status(status: StatusCode) -> Response {
+        Response::new().with_status(status)
+    }
+
+    // Turns the event loop until the condition holds, failing after a while.
+    fn run_until<F: Fn() -> bool>(core: &mut Core, done: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            core.turn(Some(Duration::from_millis(10)));
+        }
+    }
+
+    #[test]
+    fn retries_until_delivered() {
+        let mut core = Core::new().unwrap();
+        let rate_limited = status(StatusCode::TooManyRequests)
+            .with_header(RetryAfter::Delay(Duration::from_secs(2)));
+        let replies = vec![rate_limited, status(StatusCode::ServiceUnavailable)];
+        let (url, posts) = serve(&core, replies);
+        let webhook = Rc::new(Webhook::new(&url, &core.handle()).unwrap());
+        let retry = Rc::new(RetryCfg {
+            max_attempts: Some(5),
+            max_delay: Some(1),
+            dead_letter_path: None,
+        });
+
+        let delivery = deliver(webhook, Timer::default(), retry, "hello".to_owned(), 1);
+        core.run(delivery).unwrap();
+
+        let posts = posts.borrow();
+        assert_eq!(posts.len(), 3);
+        assert!(posts.iter().all(|&(_, ref body)| body == "hello"));
+        // as long as slack asked for rather than the 1s of the first backoff
+        assert!(posts[1].0 - posts[0].0 >= Duration::from_millis(1900));
+        // the second backoff of 2s is capped by max_delay
+        assert!(posts[2].0 - posts[1].0 >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut core = Core::new().unwrap();
+        let replies = (0..5).map(|_| status(StatusCode::InternalServerError)).collect();
+        let (url, posts) = serve(&core, replies);
+        let webhook = Rc::new(Webhook::new(&url, &core.handle()).unwrap());
+        let path = env::temp_dir().join(format!("slagw-dead-letters-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let retry = Rc::new(RetryCfg {
+            max_attempts: Some(3),
+            max_delay: Some(0),
+            dead_letter_path: Some(path.clone()),
+        });
+
+        let delivery = deliver(webhook, Timer::default(), retry, "lost".to_owned(), 1);
+        core.run(delivery).unwrap();
+
+        assert_eq!(posts.borrow().len(), 3);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "lost\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let mut core = Core::new().unwrap();
+        let (url, posts) = serve(&core, vec![status(StatusCode::BadRequest)]);
+        let webhook = Rc::new(Webhook::new(&url, &core.handle()).unwrap());
+        let retry = Rc::new(RetryCfg::default());
+
+        let delivery = deliver(webhook, Timer::default(), retry, "bad".to_owned(), 1);
+        core.run(delivery).unwrap();
+
+        assert_eq!(posts.borrow().len(), 1);
+    }
+
+    #[test]
+    fn delivers_in_order_per_channel() {
+        let mut core = Core::new().unwrap();
+        let (url, posts) = serve(&core, vec![status(StatusCode::ServiceUnavailable)]);
+        let mut channels = HashMap::new();
+        channels.insert("general".to_owned(), "#general".to_owned());
+        let cfg = SlackCfg {
+            secret: String::new(),
+            hook_url: url,
+            backlog_limit: None,
+            retry: Some(RetryCfg {
+                max_attempts: None,
+                max_delay: Some(0),
+                dead_letter_path: None,
+            }),
+            channels: channels,
+        };
+        let (mut tx, rx) = mpsc::channel(8);
+        let sender = SlackSender::new(
+            rx,
+            cfg,
+            &core.handle(),
+            Journal::disabled().shared(),
+            HashMap::new(),
+            LoopGuard::new(LoopCfg::default()),
+        ).unwrap();
+        sender.process(&core.handle());
+        for text in &["first", "second"] {
+            let msg = PrivMsg {
+                nick: "alice".to_owned(),
+                chan: "#general".to_owned(),
+                msg: text.to_string(),
+                ..Default::default()
+            };
+            tx.try_send(SlackMsg::OutMsg(msg)).unwrap();
+        }
+
+        run_until(&mut core, || posts.borrow().len() >= 3);
+        let texts: Vec<String> = posts
+            .borrow()
+            .iter()
+            .map(|&(_, ref body)| {
+                let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+                payload["text"].as_str().unwrap().to_owned()
+            })
+            .collect();
+        // the second message waits for the first one's retry
+        assert_eq!(texts, vec!["first", "first", "second"]);
+    }
+
+    #[test]
+    fn only_counts_its_own_pins() {
+        let own = (Some("U1".to_owned()), Some("B1".to_owned()));
+        let msg = |json: &str| -> serde_json::Value { serde_json::from_str(json).unwrap() };
+        assert!(is_own(&own, &msg(r#"{"user": "U1", "text": "3 on IRC:"}"#)));
+        assert!(is_own(&own, &msg(r#"{"bot_id": "B1", "text": "3 on IRC:"}"#)));
+        assert!(!is_own(&own, &msg(r#"{"user": "U2", "text": "3 on IRC:"}"#)));
+        assert!(!is_own(&(None, None), &msg(r#"{"text": "3 on IRC:"}"#)));
+    }
+
+    #[test]
+    fn recognises_presence_views() {
+        assert!(is_presence("3 on IRC: @alice, +bob, carol"));
+        assert!(is_presence("0 on IRC:"));
+        assert!(!is_presence("alice on IRC: hi"));
+        assert!(!is_presence("3 people on IRC: @alice, +bob, carol"));
+    }
+
+    #[test]
+    fn parses_slack_timestamps() {
+        assert_eq!(SlackTs::parse("1512085950.000216"), Some(SlackTs(1512085950, 216)));
+        assert_eq!(SlackTs::parse("1512085950"), Some(SlackTs(1512085950, 0)));
+        assert_eq!(SlackTs::parse("1512085950.5"), Some(SlackTs(1512085950, 500000)));
+        assert_eq!(SlackTs(1512085950, 216).to_string(), "1512085950.000216");
+        for ts in &["", ".5", "1512085950.", "1512085950.0000001", "15120x.1", "-1.2", "1.2.3"] {
+            assert_eq!(SlackTs::parse(ts), None, "{}", ts);
+        }
+    }
+
+    #[test]
+    fn orders_slack_timestamps() {
+        let ts = |s| SlackTs::parse(s).unwrap();
+        assert!(ts("1512085950.000216") < ts("1512085950.000300"));
+        // as floats these would be the same
+        assert!(ts("1512085950.000001") < ts("1512085950.000002"));
+        assert!(ts("1512085950.999999") < ts("1512085951.000000"));
+        assert!(ts("1512085950.1") > ts("1512085950.099999"));
+        assert_eq!(ts("1512085950.100000"), ts("1512085950.1"));
+    }
+
+    #[test]
+    fn drops_messages_already_seen() {
+        let mut seen = HashMap::new();
+        assert!(is_new(&mut seen, "C1", "1512085950.000216"));
+        // replayed after reconnecting
+        assert!(!is_new(&mut seen, "C1", "1512085950.000216"));
+        assert!(!is_new(&mut seen, "C1", "1512085949.000000"));
+        assert!(is_new(&mut seen, "C1", "1512085950.000217"));
+        // channels are separate
+        assert!(is_new(&mut seen, "C2", "1512085950.000100"));
+        // malformed timestamps are relayed without affecting what's been seen
+        assert!(is_new(&mut seen, "C1", "soon"));
+        assert!(is_new(&mut seen, "C1", "soon"));
+        assert_eq!(seen["C1"], SlackTs(1512085950, 217));
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use futures::{future, Future, Stream};
+use hyper::{self, Client, Method, Request, StatusCode, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::{ContentType, RetryAfter};
+use hyper_tls::HttpsConnector;
+use serde_json::{self, Value};
+use tokio_core::reactor;
+use url::form_urlencoded;
+
+use errors::SlagErr;
+
+pub const API_URL: &'static str = "https://slack.com/api/";
+
+/// Posts payloads to a slack incoming webhook, reporting enough about failures
+/// to decide whether a post should be retried.
+pub struct Webhook {
+    client: Client<HttpsConnector<HttpConnector>>,
+    url: Uri,
+}
+
+#[derive(Debug)]
+pub enum PostError {
+    /// HTTP 429, optionally with the delay slack asked for.
+    RateLimited(Option<Duration>),
+    Status(StatusCode, String),
+    Transport(hyper::Error),
+}
+
+impl PostError {
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            PostError::RateLimited(_) => true,
+            PostError::Status(status, _) => status.is_server_error(),
+            PostError::Transport(_) => true,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self {
+            PostError::RateLimited(delay) => delay,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PostError::RateLimited(Some(d)) => write!(f, "rate limited for {}s", d.as_secs()),
+            PostError::RateLimited(None) => write!(f, "rate limited"),
+            PostError::Status(status, ref body) => write!(f, "{}: {}", status, body),
+            PostError::Transport(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Webhook {
+    pub fn new(url: &str, handle: &reactor::Handle) -> Result<Webhook, SlagErr> {
+        // hyper-tls links against its own native-tls version
+        let connector = HttpsConnector::new(1, handle).map_err(|e| e.to_string())?;
+        let client = Client::configure().connector(connector).build(handle);
+        Ok(Webhook {
+            client: client,
+            url: url.parse()?,
+        })
+    }
+
+    pub fn post(&self, body: String) -> Box<Future<Item = (), Error = PostError>> {
+        let mut req = Request::new(Method::Post, self.url.clone());
+        req.headers_mut().set(ContentType::json());
+        req.set_body(body);
+
+        let resp = self.client
+            .request(req)
+            .map_err(PostError::Transport)
+            .and_then(|resp| {
+                let status = resp.status();
+                if status.is_success() {
+                    return Box::new(resp.body().for_each(|_| Ok(())).map_err(PostError::Transport))
+                        as Box<Future<Item = (), Error = PostError>>;
+                }
+                if status == StatusCode::TooManyRequests {
+                    let delay = resp.headers()
+                        .get::<RetryAfter>()
+                        .map(|r| retry_delay(r, SystemTime::now()));
+                    return Box::new(future::err(PostError::RateLimited(delay)));
+                }
+                Box::new(
+                    resp.body()
+                        .concat2()
+                        .map_err(PostError::Transport)
+                        .and_then(move |body| {
+                            let body = String::from_utf8_lossy(&body).into_owned();
+                            Err(PostError::Status(status, body))
+                        }),
+                )
+            });
+        Box::new(resp)
+    }
+
+    /// Calls a slack Web API method for what webhooks can't do, e.g.
+    /// `conversations.setTopic`.
+    pub fn call(
+        &self,
+        method: &str,
+        token: &str,
+        params: &[(&str, &str)],
+    ) -> Box<Future<Item = Value, Error = PostError>> {
+        let uri: Uri = match format!("{}{}", API_URL, method).parse() {
+            Ok(u) => u,
+            Err(e) => {
+                let err = PostError::Status(StatusCode::BadRequest, format!("{}", e));
+                return Box::new(future::err(err));
+            }
+        };
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", token)
+            .extend_pairs(params.iter())
+            .finish();
+        let mut req = Request::new(Method::Post, uri);
+        req.headers_mut().set(ContentType::form_url_encoded());
+        req.set_body(body);
+
+        let resp = self.client
+            .request(req)
+            .map_err(PostError::Transport)
+            .and_then(|resp| {
+                let status = resp.status();
+                resp.body()
+                    .concat2()
+                    .map_err(PostError::Transport)
+                    .and_then(move |body| {
+                        // failures are reported in the body, with a 200
+                        let reply: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                        if status.is_success() && reply["ok"] == true {
+                            return Ok(reply);
+                        }
+                        let reason = reply["error"].as_str().unwrap_or("unknown error");
+                        Err(PostError::Status(status, reason.to_owned()))
+                    })
+            });
+        Box::new(resp)
+    }
+}
+
+// Slack asks for a number of seconds, but an HTTP date is just as valid. A date
+// that has passed already means right away.
+fn retry_delay(retry_after: &RetryAfter, now: SystemTime) -> Duration {
+    match *retry_after {
+        RetryAfter::Delay(delay) => delay,
+        RetryAfter::DateTime(date) => SystemTime::from(date)
+            .duration_since(now)
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HttpDate;
+
+    use super::*;
+
+    #[test]
+    fn retry_after_in_seconds() {
+        let delay = retry_delay(&RetryAfter::Delay(Duration::from_secs(30)), SystemTime::now());
+        assert_eq!(delay, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn retry_after_as_date() {
+        let date: HttpDate = "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap();
+        let at = SystemTime::from(date);
+        let retry_after = RetryAfter::DateTime(date);
+
+        let before = at - Duration::from_secs(90);
+        assert_eq!(retry_delay(&retry_after, before), Duration::from_secs(90));
+        assert_eq!(retry_delay(&retry_after, at), Duration::from_secs(0));
+        let after = at + Duration::from_secs(5);
+        assert_eq!(retry_delay(&retry_after, after), Duration::from_secs(0));
+    }
+}